@@ -0,0 +1,80 @@
+//! `tpcli accounts`: every identity a token can be found for.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    error::Error,
+    now,
+    output::{epoch_time, print_table},
+    sources::{self, SourceOptions},
+    AccountType,
+};
+
+/// Latest expiry of the tokens found for one identity in one source, and
+/// whether any of them can be renewed.
+struct Expiry {
+    expiration: u64,
+    renewable: bool,
+}
+
+/// Searches every source for both account types and prints one row per
+/// identity and source.
+pub fn list(options: &SourceOptions) -> Result<(), Error> {
+    let now = now();
+
+    // (user, tenant, account type, source) -> expiry
+    let mut accounts: BTreeMap<(String, String, &str, String), Expiry> = BTreeMap::new();
+    for app in sources::APPS {
+        let source = match sources::from_name(app, options) {
+            Some(source) => source,
+            None => continue,
+        };
+        for account_type in [AccountType::Microsoft, AccountType::Live] {
+            let tokens = match source
+                .discover(account_type)
+                .and_then(|_| source.tokens(account_type))
+            {
+                Ok(tokens) => tokens,
+                Err(_) => continue,
+            };
+            for token in tokens {
+                let claims = token.claims();
+                let key = (
+                    claims.user().unwrap_or("-").to_string(),
+                    claims.tid.clone().unwrap_or_else(|| "-".to_string()),
+                    claims.account_type().unwrap_or(account_type).name(),
+                    source.describe(),
+                );
+                let expiry = accounts.entry(key).or_insert(Expiry {
+                    expiration: 0,
+                    renewable: false,
+                });
+                expiry.expiration = expiry.expiration.max(token.expiration);
+                expiry.renewable |= token.refresh.is_some();
+            }
+        }
+    }
+
+    if accounts.is_empty() {
+        println!("No accounts found. Sign in to Teams, or run `tpcli login`.");
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 5]> = accounts
+        .into_iter()
+        .map(|((user, tenant, account_type, source), expiry)| {
+            let expires_at = epoch_time(expiry.expiration);
+            let expires = if expiry.expiration > now {
+                expires_at
+            } else if expiry.renewable {
+                format!("expired {} (renewable)", expires_at)
+            } else {
+                format!("expired {}", expires_at)
+            };
+            [user, tenant, account_type.to_string(), expires, source]
+        })
+        .collect();
+
+    print_table(["USER", "TENANT", "ACCOUNT", "EXPIRES", "SOURCE"], &rows);
+    Ok(())
+}
@@ -0,0 +1,453 @@
+//! Encrypted on-disk cache of discovered tokens.
+//!
+//! Reading a browser's Local Storage or the Teams app's cookies means scanning
+//! a database, so the token chosen from a source is kept in `tokens.enc` in the
+//! cache directory, encrypted with AES-256-GCM. Only the access token is kept,
+//! never a refresh token. Each entry records a fingerprint of the files it was
+//! read from (their modification times); it is used until the token is close
+//! to expiring or the files change.
+//!
+//! The key is `cache_key` from the config, or `cache.key` in the config
+//! directory, which is generated the first time a token is cached.
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, OsRng},
+    AeadCore, Aes256Gcm, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    config::{config_dir, Config},
+    error::Error,
+    now,
+    refresh::RENEW_MARGIN_SECS,
+    sources::{AccountFilter, SourceError, Token, TokenSource},
+    AccountType,
+};
+
+const NONCE_LEN: usize = 12;
+
+/// Modification times of the files a token was read from.
+pub type Fingerprint = Vec<(PathBuf, u128)>;
+
+/// Fingerprints the given files. Missing files are left out, so a file
+/// appearing or disappearing also changes the fingerprint.
+pub fn fingerprint_files(paths: impl IntoIterator<Item = PathBuf>) -> Fingerprint {
+    let mut fingerprint: Fingerprint = paths
+        .into_iter()
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).ok()?.modified().ok()?;
+            let nanos = modified.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+            Some((path, nanos))
+        })
+        .collect();
+    fingerprint.sort();
+    fingerprint
+}
+
+/// Fingerprints every file directly inside `dir`.
+pub fn fingerprint_dir(dir: &Path) -> Fingerprint {
+    let paths = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path());
+    fingerprint_files(paths)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry {
+    token: Token,
+    account_type: AccountType,
+    fingerprint: Fingerprint,
+}
+
+pub fn cache_dir() -> PathBuf {
+    if cfg!(target_os = "macos") {
+        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+        home.join("Library").join("Caches").join("tpcli")
+    } else if cfg!(target_os = "windows") {
+        PathBuf::from(env::var("LOCALAPPDATA").unwrap_or_default())
+            .join("tpcli")
+            .join("cache")
+    } else {
+        match env::var("XDG_CACHE_HOME") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("tpcli"),
+            _ => {
+                let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+                home.join(".cache").join("tpcli")
+            }
+        }
+    }
+}
+
+/// Writes `contents` to `path`, readable only by the current user.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+pub struct TokenCache {
+    path: PathBuf,
+    /// `cache_key` from the config.
+    configured_key: Option<String>,
+    /// Where the key is kept when the config has none.
+    key_path: PathBuf,
+    /// Set up on first use, so commands that never cache a token don't create
+    /// a key. `None` if the key is unusable.
+    cipher: OnceLock<Option<Aes256Gcm>>,
+}
+
+impl TokenCache {
+    pub fn new(config: &Config) -> TokenCache {
+        TokenCache {
+            path: cache_dir().join("tokens.enc"),
+            configured_key: config.cache_key.clone(),
+            key_path: config_dir().join("cache.key"),
+            cipher: OnceLock::new(),
+        }
+    }
+
+    fn cipher(&self) -> Option<&Aes256Gcm> {
+        self.cipher
+            .get_or_init(|| match self.open_cipher() {
+                Ok(cipher) => Some(cipher),
+                Err(err) => {
+                    eprintln!("Token cache disabled: {}", err);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// Reads the key, creating it if neither the config nor the config
+    /// directory has one yet.
+    fn open_cipher(&self) -> Result<Aes256Gcm, Error> {
+        let encoded_key = match &self.configured_key {
+            Some(key) => key.clone(),
+            None => {
+                if self.key_path.exists() {
+                    fs::read_to_string(&self.key_path)?.trim().to_string()
+                } else {
+                    let key = base64::encode(Aes256Gcm::generate_key(&mut OsRng));
+                    write_private(&self.key_path, key.as_bytes())?;
+                    key
+                }
+            }
+        };
+        let key = base64::decode(encoded_key)
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| "cache key must be 32 bytes, base64 encoded".to_string())?;
+
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    /// Every cached entry. An unreadable cache (wrong key, corrupted, from an
+    /// older version) is treated as empty and overwritten on the next store.
+    fn load(&self, cipher: &Aes256Gcm) -> HashMap<String, CacheEntry> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) if contents.len() > NONCE_LEN => contents,
+            _ => return HashMap::new(),
+        };
+        let (nonce, ciphertext) = contents.split_at(NONCE_LEN);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+            .and_then(|plaintext| serde_json::from_slice(&plaintext).ok())
+            .unwrap_or_default()
+    }
+
+    fn store(
+        &self,
+        cipher: &Aes256Gcm,
+        entries: &HashMap<String, CacheEntry>,
+    ) -> Result<(), Error> {
+        let plaintext = serde_json::to_vec(entries)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| "failed to encrypt token cache".to_string())?;
+        let mut contents = nonce.to_vec();
+        contents.extend(ciphertext);
+        write_private(&self.path, &contents)
+    }
+
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        // Nothing was ever cached without a key, so don't create one.
+        if self.configured_key.is_none() && !self.key_path.exists() {
+            return None;
+        }
+        self.load(self.cipher()?).remove(key)
+    }
+
+    fn put(&self, key: String, entry: CacheEntry) -> Result<(), Error> {
+        let cipher = match self.cipher() {
+            Some(cipher) => cipher,
+            None => return Ok(()),
+        };
+        let mut entries = self.load(cipher);
+        entries.insert(key, entry);
+        self.store(cipher, &entries)
+    }
+}
+
+/// Wraps a source so the token chosen from it is cached. Sources that can't
+/// fingerprint their data are passed through uncached.
+pub struct CachedSource {
+    pub inner: Box<dyn TokenSource>,
+    pub cache: Arc<TokenCache>,
+}
+
+impl CachedSource {
+    /// Entries are per source and per lookup, since `--account`, `--user` and
+    /// `--tenant` choose between the source's tokens.
+    fn key(&self, account_type: Option<AccountType>, filter: &AccountFilter) -> String {
+        format!(
+            "{}|{}|{}|{}",
+            account_type.map_or("any", AccountType::name),
+            self.inner.describe(),
+            filter.user.as_deref().unwrap_or_default(),
+            filter.tenant.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+impl TokenSource for CachedSource {
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn discover(&self, account_type: AccountType) -> Result<(), SourceError> {
+        self.inner.discover(account_type)
+    }
+
+    fn fingerprint(&self, account_type: AccountType) -> Option<Fingerprint> {
+        self.inner.fingerprint(account_type)
+    }
+
+    fn account_agnostic(&self) -> bool {
+        self.inner.account_agnostic()
+    }
+
+    fn paths(&self, account_type: AccountType) -> Vec<PathBuf> {
+        self.inner.paths(account_type)
+    }
+
+    fn tokens(&self, account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        self.inner.tokens(account_type)
+    }
+
+    fn changed(&self) -> bool {
+        self.inner.changed()
+    }
+
+    fn cached(
+        &self,
+        account_type: Option<AccountType>,
+        filter: &AccountFilter,
+    ) -> Option<(Token, AccountType)> {
+        let now = now();
+        let entry = self.cache.get(&self.key(account_type, filter))?;
+        if entry.token.expiration <= now + RENEW_MARGIN_SECS
+            || self.inner.fingerprint(entry.account_type)? != entry.fingerprint
+        {
+            return None;
+        }
+        Some((entry.token, entry.account_type))
+    }
+
+    fn remember(
+        &self,
+        account_type: Option<AccountType>,
+        filter: &AccountFilter,
+        token: &Token,
+        token_type: AccountType,
+    ) {
+        let fingerprint = match self.inner.fingerprint(token_type) {
+            Some(fingerprint) => fingerprint,
+            None => return,
+        };
+        let entry = CacheEntry {
+            // A token renewed from the refresh token is found in the source
+            // again, so only the access token is kept.
+            token: Token {
+                refresh: None,
+                ..token.clone()
+            },
+            account_type: token_type,
+            fingerprint,
+        };
+        if let Err(err) = self.cache.put(self.key(account_type, filter), entry) {
+            eprintln!("Failed to update token cache: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{jwt, refresh::RefreshGrant, sources};
+
+    /// A source holding one renewable token, read from `file`.
+    struct FileSource {
+        file: PathBuf,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl TokenSource for FileSource {
+        fn describe(&self) -> String {
+            "file".to_string()
+        }
+
+        fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+            Ok(())
+        }
+
+        fn tokens(&self, _account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![Token {
+                token: fs::read_to_string(&self.file)?,
+                expiration: 4102444800,
+                refresh: Some(RefreshGrant {
+                    token_url: "https://login.example/token".to_string(),
+                    client_id: "client".to_string(),
+                    refresh_token: "refresh secret".to_string(),
+                    scope: "scope".to_string(),
+                    origin: None,
+                    save_to_login: false,
+                }),
+                origin: Default::default(),
+            }])
+        }
+
+        fn fingerprint(&self, _account_type: AccountType) -> Option<Fingerprint> {
+            Some(fingerprint_files([self.file.clone()]))
+        }
+    }
+
+    fn setup(dir: &Path) -> (Vec<Box<dyn TokenSource>>, Arc<AtomicUsize>, PathBuf) {
+        let file = dir.join("token");
+        fs::write(
+            &file,
+            jwt::fake(serde_json::json!({ "tid": "contoso", "exp": 4102444800u64 })),
+        )
+        .unwrap();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let cache = TokenCache {
+            path: dir.join("tokens.enc"),
+            configured_key: None,
+            key_path: dir.join("cache.key"),
+            cipher: OnceLock::new(),
+        };
+        let source = CachedSource {
+            inner: Box::new(FileSource {
+                file: file.clone(),
+                reads: reads.clone(),
+            }),
+            cache: Arc::new(cache),
+        };
+        (vec![Box::new(source)], reads, file)
+    }
+
+    #[test]
+    fn reuses_the_chosen_token_until_the_source_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sources, reads, file) = setup(dir.path());
+        let filter = AccountFilter::default();
+
+        sources::find_token(&sources, Some(AccountType::Microsoft), &filter).unwrap();
+        let (token, account_type) =
+            sources::find_token(&sources, Some(AccountType::Microsoft), &filter).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        assert_eq!(account_type, AccountType::Microsoft);
+        assert!(token.refresh.is_none());
+
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(UNIX_EPOCH)
+            .unwrap();
+        let (token, _) =
+            sources::find_token(&sources, Some(AccountType::Microsoft), &filter).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert!(token.refresh.is_some());
+    }
+
+    #[test]
+    fn keeps_lookups_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sources, reads, _) = setup(dir.path());
+
+        sources::find_token(
+            &sources,
+            Some(AccountType::Microsoft),
+            &AccountFilter::default(),
+        )
+        .unwrap();
+        let filter = AccountFilter {
+            tenant: Some("contoso".to_string()),
+            ..AccountFilter::default()
+        };
+        sources::find_token(&sources, Some(AccountType::Microsoft), &filter).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn stores_only_the_access_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sources, _, _) = setup(dir.path());
+        assert!(!dir.path().join("cache.key").exists());
+
+        sources::find_token(
+            &sources,
+            Some(AccountType::Microsoft),
+            &AccountFilter::default(),
+        )
+        .unwrap();
+        let key =
+            base64::decode(fs::read_to_string(dir.path().join("cache.key")).unwrap()).unwrap();
+        let contents = fs::read(dir.path().join("tokens.enc")).unwrap();
+        let (nonce, ciphertext) = contents.split_at(NONCE_LEN);
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .unwrap();
+        let plaintext = String::from_utf8(plaintext).unwrap();
+        assert!(plaintext.contains("\"account_type\":\"microsoft\""));
+        assert!(!plaintext.contains("refresh secret"));
+    }
+
+    #[test]
+    fn uncached_lookups_keep_the_refresh_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sources, reads, _) = setup(dir.path());
+        let filter = AccountFilter::default();
+
+        sources::find_token(&sources, Some(AccountType::Microsoft), &filter).unwrap();
+        let (token, _) =
+            sources::find_token_uncached(&sources, Some(AccountType::Microsoft), &filter).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert!(token.refresh.is_some());
+    }
+}
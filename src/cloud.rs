@@ -0,0 +1,251 @@
+//! National clouds, and the endpoints `tpcli` calls in each.
+//!
+//! Every URL `tpcli` sends requests to comes from [`Endpoints`]: the presence
+//! service, Microsoft Graph, Trouter and the identity provider. They default
+//! to the ones of the configured [`Cloud`], and each can be overridden in the
+//! config.
+
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+use crate::{config::Config, AccountType};
+
+/// Every named `--cloud` value.
+pub const CLOUDS: &[&str] = &["commercial", "live", "gcc", "gcch", "dod"];
+
+/// The Microsoft 365 environment the account is in.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(try_from = "String")]
+pub enum Cloud {
+    /// Worldwide Microsoft 365, and teams.live.com for personal accounts.
+    #[default]
+    Commercial,
+    /// Personal (teams.live.com) accounts only.
+    Live,
+    /// US Government Community Cloud.
+    Gcc,
+    /// US Government Community Cloud High.
+    Gcch,
+    /// US Department of Defense.
+    Dod,
+    /// One base URL serving every service, e.g. a local mock server.
+    Custom(String),
+}
+
+impl FromStr for Cloud {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "commercial" => Ok(Cloud::Commercial),
+            "live" => Ok(Cloud::Live),
+            "gcc" => Ok(Cloud::Gcc),
+            "gcch" => Ok(Cloud::Gcch),
+            "dod" => Ok(Cloud::Dod),
+            url if url.starts_with("https://") || url.starts_with("http://") => {
+                Ok(Cloud::Custom(url.trim_end_matches('/').to_string()))
+            }
+            _ => Err(format!(
+                "Unknown cloud `{}`: expected one of {} or a base URL",
+                s,
+                CLOUDS.join(", ")
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Cloud {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cloud {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cloud::Commercial => "commercial",
+            Cloud::Live => "live",
+            Cloud::Gcc => "gcc",
+            Cloud::Gcch => "gcch",
+            Cloud::Dod => "dod",
+            Cloud::Custom(url) => url,
+        })
+    }
+}
+
+impl Cloud {
+    /// The only kind of account the cloud has, if it has one kind.
+    pub fn account_type(&self) -> Option<AccountType> {
+        match self {
+            Cloud::Live => Some(AccountType::Live),
+            Cloud::Gcc | Cloud::Gcch | Cloud::Dod => Some(AccountType::Microsoft),
+            Cloud::Commercial | Cloud::Custom(_) => None,
+        }
+    }
+
+    /// Origins Teams web is served from, whose browser storage holds its
+    /// tokens. The first is the one to renew those tokens from. A custom base
+    /// URL may serve Teams web itself, and stands in for the commercial
+    /// services otherwise.
+    pub fn teams_origins(&self) -> Vec<String> {
+        let origins: &[&str] = match self {
+            Cloud::Commercial | Cloud::Custom(_) => &[
+                "https://teams.microsoft.com",
+                "https://teams.live.com",
+                "https://teams.cloud.microsoft",
+            ],
+            Cloud::Live => &["https://teams.live.com"],
+            Cloud::Gcc => &["https://teams.microsoft.com"],
+            Cloud::Gcch => &[
+                "https://gov.teams.microsoft.us",
+                "https://teams.microsoft.us",
+            ],
+            Cloud::Dod => &[
+                "https://dod.teams.microsoft.us",
+                "https://teams.microsoft.us",
+            ],
+        };
+        let mut origins: Vec<String> = origins.iter().map(|origin| origin.to_string()).collect();
+        if let Cloud::Custom(base) = self {
+            // The scheme and host, without any path.
+            let host_start = base.find("://").map_or(0, |i| i + 3);
+            let end = base[host_start..]
+                .find('/')
+                .map_or(base.len(), |i| host_start + i);
+            origins.insert(0, base[..end].to_string());
+        }
+        origins
+    }
+
+    /// The resource presence tokens for `account_type` are issued for. A
+    /// custom base URL stands in for the commercial services, so it takes
+    /// their tokens.
+    pub fn audience(&self, account_type: AccountType) -> String {
+        match (self, account_type) {
+            (Cloud::Commercial | Cloud::Custom(_), AccountType::Microsoft) => {
+                "https://presence.teams.microsoft.com/".to_string()
+            }
+            (Cloud::Commercial | Cloud::Custom(_) | Cloud::Live, AccountType::Live)
+            | (Cloud::Live, AccountType::Microsoft) => {
+                "https://presence.teams.live.com/".to_string()
+            }
+            (Cloud::Gcc, _) => "https://presence.gcc.teams.microsoft.com/".to_string(),
+            (Cloud::Gcch, _) => "https://presence.gov.teams.microsoft.us/".to_string(),
+            (Cloud::Dod, _) => "https://presence.dod.teams.microsoft.us/".to_string(),
+        }
+    }
+
+    fn presence(&self, account_type: AccountType) -> String {
+        match self {
+            Cloud::Custom(base) => format!("{}/", base),
+            _ => self.audience(account_type),
+        }
+    }
+
+    fn graph(&self) -> String {
+        match self {
+            Cloud::Custom(base) => base.clone(),
+            Cloud::Gcch => "https://graph.microsoft.us".to_string(),
+            Cloud::Dod => "https://dod-graph.microsoft.us".to_string(),
+            Cloud::Commercial | Cloud::Live | Cloud::Gcc => {
+                "https://graph.microsoft.com".to_string()
+            }
+        }
+    }
+
+    fn graph_scope(&self) -> String {
+        match self {
+            Cloud::Custom(_) => format!("{}/.default", Cloud::Commercial.graph()),
+            _ => format!("{}/.default", self.graph()),
+        }
+    }
+
+    fn trouter(&self) -> String {
+        match self {
+            Cloud::Custom(base) => format!("{}/v4/a", base),
+            Cloud::Commercial | Cloud::Live => {
+                "https://go.trouter.teams.microsoft.com/v4/a".to_string()
+            }
+            Cloud::Gcc => "https://go.trouter.gcc.teams.microsoft.com/v4/a".to_string(),
+            Cloud::Gcch => "https://go.trouter.gov.teams.microsoft.us/v4/a".to_string(),
+            Cloud::Dod => "https://go.trouter.dod.teams.microsoft.us/v4/a".to_string(),
+        }
+    }
+
+    fn authority(&self) -> String {
+        match self {
+            Cloud::Custom(base) => base.clone(),
+            Cloud::Gcch | Cloud::Dod => "https://login.microsoftonline.us".to_string(),
+            Cloud::Commercial | Cloud::Live | Cloud::Gcc => {
+                "https://login.microsoftonline.com".to_string()
+            }
+        }
+    }
+}
+
+/// Where to send requests: the endpoints of the cloud, unless the config
+/// overrides them.
+#[derive(Debug, Default)]
+pub struct Endpoints {
+    pub cloud: Cloud,
+    presence_url: Option<String>,
+    graph_url: Option<String>,
+    trouter_url: Option<String>,
+    authority: Option<String>,
+}
+
+impl Endpoints {
+    pub fn new(cloud: Cloud, config: &Config) -> Self {
+        Endpoints {
+            cloud,
+            presence_url: config.presence_url.clone(),
+            graph_url: config.graph_url.clone(),
+            trouter_url: config.trouter_url.clone(),
+            authority: config.authority.clone(),
+        }
+    }
+
+    /// Base URL of the presence service for `account_type`, ending in `/`.
+    pub fn presence(&self, account_type: AccountType) -> String {
+        match &self.presence_url {
+            Some(url) => format!("{}/", url.trim_end_matches('/')),
+            None => self.cloud.presence(account_type),
+        }
+    }
+
+    /// The resource presence tokens are issued for.
+    pub fn audience(&self, account_type: AccountType) -> String {
+        self.cloud.audience(account_type)
+    }
+
+    /// Base URL of Microsoft Graph, without the API version.
+    pub fn graph(&self) -> String {
+        match &self.graph_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => self.cloud.graph(),
+        }
+    }
+
+    /// Scope to redeem a refresh token for to call Graph with whatever
+    /// permissions the client has been granted.
+    pub fn graph_scope(&self) -> String {
+        self.cloud.graph_scope()
+    }
+
+    /// Where to ask for a Trouter socket.
+    pub fn trouter(&self) -> String {
+        self.trouter_url
+            .clone()
+            .unwrap_or_else(|| self.cloud.trouter())
+    }
+
+    /// Identity provider for `tpcli login`, e.g. `https://login.microsoftonline.com`.
+    pub fn authority(&self) -> String {
+        match &self.authority {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => self.cloud.authority(),
+        }
+    }
+}
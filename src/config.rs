@@ -0,0 +1,209 @@
+//! User configuration, read from `config.toml` in the tpcli config directory.
+
+use serde::Deserialize;
+use std::{env, fmt, fs, io, path::PathBuf, time::Duration};
+
+use crate::{cloud::Cloud, Backend};
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Password used to decrypt `v11` Teams app cookies on Linux. This is the
+    /// "Chrome Safe Storage" / "Microsoft Teams Safe Storage" secret held by the
+    /// desktop keyring.
+    pub cookie_password: Option<String>,
+    /// Command that prints a token, e.g. `pass show teams/token`. See
+    /// `sources::helper` for the protocol.
+    pub token_helper: Option<String>,
+    /// Tenant (directory) ID or domain the token should belong to.
+    pub tenant: Option<String>,
+    /// API `tpcli <status>` sets presence with: `teams` (the default) or
+    /// `graph`.
+    pub backend: Option<Backend>,
+    /// Cloud the account is in, which sets every endpoint below that isn't
+    /// given. Defaults to the commercial cloud.
+    pub cloud: Option<Cloud>,
+    /// Identity provider used by `tpcli login`, e.g.
+    /// `https://login.microsoftonline.com`.
+    pub authority: Option<String>,
+    /// OAuth client ID used by `tpcli login`.
+    pub client_id: Option<String>,
+    /// Cache tokens read from browsers and the Teams app. On by default.
+    pub token_cache: Option<bool>,
+    /// Base64 encoded 256-bit key for the token cache. Defaults to the key in
+    /// `cache.key` in the config directory.
+    pub cache_key: Option<String>,
+    /// How long to wait for each response from Teams or Graph, e.g. `30s`.
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Option<Duration>,
+    /// How many times to retry requests that fail with a connection error,
+    /// timeout, server error or rate limiting.
+    pub retries: Option<u32>,
+    /// Delay before the first retry, e.g. `1s`. Doubled, with jitter, for each
+    /// retry after.
+    #[serde(with = "humantime_serde")]
+    pub retry_delay: Option<Duration>,
+    /// Stop retrying once this long has passed since the first attempt.
+    #[serde(with = "humantime_serde")]
+    pub retry_time_limit: Option<Duration>,
+    /// Base URL of the presence service, e.g.
+    /// `https://presence.teams.microsoft.com/`.
+    pub presence_url: Option<String>,
+    /// Base URL of Microsoft Graph, without the API version, e.g.
+    /// `https://graph.microsoft.com`.
+    pub graph_url: Option<String>,
+    /// Where to ask for a Trouter socket, for push notifications.
+    pub trouter_url: Option<String>,
+}
+
+/// Table of settings for each browser profile, keyed by the name given to
+/// `--profile`, e.g. `[profiles.Work]`. A profile's settings replace the ones
+/// at the top of the file.
+const PROFILES_KEY: &str = "profiles";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Failed to read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => {
+                write!(f, "Failed to parse {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Directory holding `config.toml` and other per-user tpcli state.
+pub fn config_dir() -> PathBuf {
+    if cfg!(target_os = "macos") {
+        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+        home.join("Library")
+            .join("Application Support")
+            .join("tpcli")
+    } else if cfg!(target_os = "windows") {
+        PathBuf::from(env::var("APPDATA").unwrap_or_default()).join("tpcli")
+    } else {
+        match env::var("XDG_CONFIG_HOME") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("tpcli"),
+            _ => {
+                let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+                home.join(".config").join("tpcli")
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `path`, or from `TPCLI_CONFIG` / the default
+    /// location, with the settings of browser `profile` applied. A missing
+    /// file at the default location is not an error.
+    pub fn load(path: Option<&str>, profile: Option<&str>) -> Result<Config, ConfigError> {
+        let path = match Config::explicit_path(path) {
+            Some(path) => path,
+            None => {
+                let path = config_dir().join("config.toml");
+                if !path.exists() {
+                    return Ok(Config::default());
+                }
+                path
+            }
+        };
+
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        Config::parse(&contents, profile).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    fn parse(contents: &str, profile: Option<&str>) -> Result<Config, toml::de::Error> {
+        let mut table: toml::value::Table = toml::from_str(contents)?;
+        let mut profiles = match table.remove(PROFILES_KEY) {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => {
+                return Err(serde::de::Error::custom(format!(
+                    "`{}` should be a table of profiles",
+                    PROFILES_KEY
+                )))
+            }
+            None => toml::value::Table::new(),
+        };
+        match profile.and_then(|profile| profiles.remove(profile)) {
+            Some(toml::Value::Table(settings)) => table.extend(settings),
+            Some(_) => {
+                return Err(serde::de::Error::custom(format!(
+                    "`{}.{}` should be a table of settings",
+                    PROFILES_KEY,
+                    profile.unwrap_or_default()
+                )))
+            }
+            None => {}
+        }
+        toml::Value::Table(table).try_into()
+    }
+
+    /// Where the config is read from: `path`, `TPCLI_CONFIG`, or the default
+    /// location.
+    pub fn path(path: Option<&str>) -> PathBuf {
+        Config::explicit_path(path).unwrap_or_else(|| config_dir().join("config.toml"))
+    }
+
+    fn explicit_path(path: Option<&str>) -> Option<PathBuf> {
+        path.map(String::from)
+            .or_else(|| env::var("TPCLI_CONFIG").ok())
+            .map(PathBuf::from)
+    }
+
+    /// The cookie password, with `TPCLI_COOKIE_PASSWORD` taking precedence over
+    /// the config file.
+    pub fn cookie_password(&self) -> Option<String> {
+        env::var("TPCLI_COOKIE_PASSWORD")
+            .ok()
+            .or_else(|| self.cookie_password.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        tenant = "contoso.com"
+        backend = "teams"
+
+        [profiles.Work]
+        backend = "graph"
+
+        [profiles."Profile 2"]
+        tenant = "fabrikam.com"
+    "#;
+
+    #[test]
+    fn applies_profile_settings() {
+        let config = Config::parse(CONFIG, None).unwrap();
+        assert_eq!(config.backend, Some(Backend::Teams));
+        assert_eq!(config.tenant.as_deref(), Some("contoso.com"));
+
+        let config = Config::parse(CONFIG, Some("Work")).unwrap();
+        assert_eq!(config.backend, Some(Backend::Graph));
+        assert_eq!(config.tenant.as_deref(), Some("contoso.com"));
+
+        let config = Config::parse(CONFIG, Some("Profile 2")).unwrap();
+        assert_eq!(config.backend, Some(Backend::Teams));
+        assert_eq!(config.tenant.as_deref(), Some("fabrikam.com"));
+
+        let config = Config::parse(CONFIG, Some("Default")).unwrap();
+        assert_eq!(config.backend, Some(Backend::Teams));
+    }
+
+    #[test]
+    fn rejects_malformed_profiles() {
+        assert!(Config::parse("profiles = 1", None).is_err());
+        assert!(Config::parse("[profiles]\nWork = 1", Some("Work")).is_err());
+        assert!(Config::parse("[profiles.Work]\nbackend = \"x\"", Some("Work")).is_err());
+    }
+}
@@ -0,0 +1,475 @@
+//! `tpcli doctor`: checks the token sources and the presence service, and
+//! optionally writes a redacted support bundle.
+
+use chrono::Utc;
+use colored::*;
+use hyper::{Client, Uri};
+use hyper_tls::HttpsConnector;
+use regex::Regex;
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    cloud::Endpoints,
+    config::Config,
+    error::Error,
+    http::RetryPolicy,
+    now,
+    output::epoch_time,
+    sources::{self, SourceError, SourceOptions, Token},
+    AccountType,
+};
+
+/// How long to wait for DNS and TLS checks.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Status {
+    Ok,
+    /// Not a problem by itself, e.g. a browser that isn't installed.
+    Info,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    status: Status,
+    label: String,
+    detail: String,
+}
+
+impl Check {
+    fn new(status: Status, label: impl Into<String>, detail: impl Into<String>) -> Self {
+        Check {
+            status,
+            label: label.into(),
+            detail: detail.into(),
+        }
+    }
+}
+
+struct Section {
+    title: String,
+    checks: Vec<Check>,
+}
+
+fn render(sections: &[Section], colored: bool) -> String {
+    let mut out = String::new();
+    for section in sections {
+        out.push_str(&format!("{}\n", section.title));
+        for check in &section.checks {
+            let (marker, color): (&str, fn(&str) -> ColoredString) = match check.status {
+                Status::Ok => ("ok", |s| s.green()),
+                Status::Info => ("--", |s| s.normal()),
+                Status::Warn => ("warn", |s| s.yellow()),
+                Status::Fail => ("FAIL", |s| s.red()),
+            };
+            let marker = format!("[{:4}]", marker);
+            let marker = if colored {
+                color(&marker).to_string()
+            } else {
+                marker
+            };
+            out.push_str(&format!("  {} {}: {}\n", marker, check.label, check.detail));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Whether another process holds a lock on `path`. LevelDB and SQLite take
+/// POSIX record locks, which `F_GETLK` reports.
+#[cfg(unix)]
+fn is_locked(path: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    // Zeroed `l_start` and `l_len` ask about the whole file.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) };
+    result == 0 && lock.l_type != libc::F_UNLCK as libc::c_short
+}
+
+/// Whether another process holds `path` open without sharing it.
+#[cfg(not(unix))]
+fn is_locked(path: &Path) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    match fs::File::open(path) {
+        Ok(_) => false,
+        Err(err) => matches!(
+            err.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+        ),
+    }
+}
+
+fn check_path(path: &Path) -> Check {
+    let label = path.display().to_string();
+    if !path.exists() {
+        return Check::new(Status::Info, label, "does not exist");
+    }
+
+    // A LevelDB directory is locked through its `LOCK` file.
+    let lock_file = if path.is_dir() {
+        path.join("LOCK")
+    } else {
+        path.to_path_buf()
+    };
+    let locked = lock_file.is_file() && is_locked(&lock_file);
+
+    let readable = if path.is_dir() {
+        fs::read_dir(path).map(|_| ())
+    } else {
+        fs::File::open(path).map(|_| ())
+    };
+    match (readable, locked) {
+        (Err(err), true) => Check::new(
+            Status::Fail,
+            label,
+            format!("locked by a running app: {}", err),
+        ),
+        (Err(err), false) => Check::new(Status::Fail, label, format!("not readable: {}", err)),
+        (Ok(()), true) => Check::new(Status::Ok, label, "readable, in use by a running app"),
+        (Ok(()), false) => Check::new(Status::Ok, label, "readable"),
+    }
+}
+
+fn check_tokens(result: Result<Vec<Token>, SourceError>, label: String, now: u64) -> Check {
+    let tokens = match result {
+        Ok(tokens) => tokens,
+        Err(err @ SourceError::NotFound(_)) | Err(err @ SourceError::Unsupported(_)) => {
+            return Check::new(Status::Info, label, err.to_string())
+        }
+        Err(err) => return Check::new(Status::Fail, label, err.to_string()),
+    };
+
+    let (valid, expired): (Vec<&Token>, Vec<&Token>) =
+        tokens.iter().partition(|token| token.expiration > now);
+    let renewable = expired
+        .iter()
+        .filter(|token| token.refresh.is_some())
+        .count();
+    let users: BTreeSet<String> = valid
+        .iter()
+        .map(|token| token.claims().user().unwrap_or("unknown user").to_string())
+        .collect();
+
+    let mut detail = format!("{} valid", valid.len());
+    if !users.is_empty() {
+        let users: Vec<String> = users.into_iter().collect();
+        detail.push_str(&format!(" ({})", users.join(", ")));
+    }
+    if let Some(latest) = valid.iter().map(|token| token.expiration).max() {
+        detail.push_str(&format!(", until {}", epoch_time(latest)));
+    }
+    if !expired.is_empty() {
+        detail.push_str(&format!(
+            ", {} expired ({} renewable)",
+            expired.len(),
+            renewable
+        ));
+    }
+
+    let status = if !valid.is_empty() || renewable > 0 {
+        Status::Ok
+    } else {
+        Status::Warn
+    };
+    Check::new(status, label, detail)
+}
+
+/// Checks that the host of `uri` resolves and that it answers, over TLS for
+/// `https` URLs.
+async fn check_host(uri: Uri) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let host = uri.host().unwrap_or_default().to_string();
+    let https = uri.scheme_str() != Some("http");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    match tokio::time::timeout(
+        NETWORK_TIMEOUT,
+        tokio::net::lookup_host((host.as_str(), port)),
+    )
+    .await
+    {
+        Ok(Ok(mut addrs)) => match addrs.next() {
+            Some(addr) => checks.push(Check::new(
+                Status::Ok,
+                format!("{} DNS", host),
+                addr.ip().to_string(),
+            )),
+            None => checks.push(Check::new(
+                Status::Fail,
+                format!("{} DNS", host),
+                "no addresses",
+            )),
+        },
+        Ok(Err(err)) => checks.push(Check::new(
+            Status::Fail,
+            format!("{} DNS", host),
+            err.to_string(),
+        )),
+        Err(_) => checks.push(Check::new(
+            Status::Fail,
+            format!("{} DNS", host),
+            "timed out",
+        )),
+    }
+
+    // Any HTTP response means the TLS handshake worked.
+    let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+    let label = format!("{} {}", host, if https { "TLS" } else { "HTTP" });
+    checks.push(
+        match tokio::time::timeout(NETWORK_TIMEOUT, client.get(uri)).await {
+            Ok(Ok(resp)) => Check::new(
+                Status::Ok,
+                label,
+                format!("connected (HTTP {})", resp.status().as_u16()),
+            ),
+            Ok(Err(err)) => Check::new(Status::Fail, label, err.to_string()),
+            Err(_) => Check::new(Status::Fail, label, "timed out"),
+        },
+    );
+    checks
+}
+
+fn config_section(config: &Config, config_path: &Path) -> Section {
+    let mut checks = vec![if config_path.is_file() {
+        Check::new(Status::Ok, config_path.display().to_string(), "loaded")
+    } else {
+        Check::new(
+            Status::Info,
+            config_path.display().to_string(),
+            "does not exist, using defaults",
+        )
+    }];
+    // Secrets, and commands that may contain them, are only reported as set.
+    let set = |value: bool| if value { "set" } else { "not set" };
+    checks.push(Check::new(
+        Status::Info,
+        "cookie_password",
+        set(config.cookie_password().is_some()),
+    ));
+    checks.push(Check::new(
+        Status::Info,
+        "token_helper",
+        set(config.token_helper.is_some()),
+    ));
+    checks.push(Check::new(
+        Status::Info,
+        "cache_key",
+        set(config.cache_key.is_some()),
+    ));
+    checks.push(Check::new(
+        Status::Info,
+        "backend",
+        format!("{:?}", config.backend.unwrap_or_default()).to_lowercase(),
+    ));
+    checks.push(Check::new(
+        Status::Info,
+        "cloud",
+        config.cloud.clone().unwrap_or_default().to_string(),
+    ));
+    for (name, value) in [
+        ("tenant", &config.tenant),
+        ("authority", &config.authority),
+        ("client_id", &config.client_id),
+        ("presence_url", &config.presence_url),
+        ("graph_url", &config.graph_url),
+        ("trouter_url", &config.trouter_url),
+    ] {
+        checks.push(Check::new(
+            Status::Info,
+            name,
+            value.as_deref().unwrap_or("not set"),
+        ));
+    }
+    checks.push(Check::new(
+        Status::Info,
+        "token_cache",
+        if config.token_cache.unwrap_or(true) {
+            "enabled"
+        } else {
+            "disabled"
+        },
+    ));
+    let policy = RetryPolicy::new(config);
+    checks.push(Check::new(
+        Status::Info,
+        "requests",
+        format!(
+            "{} timeout, up to {} retries starting {} apart, within {}",
+            humantime::format_duration(policy.timeout),
+            policy.retries,
+            humantime::format_duration(policy.delay),
+            humantime::format_duration(policy.time_limit)
+        ),
+    ));
+    Section {
+        title: "Configuration".to_string(),
+        checks,
+    }
+}
+
+/// Removes tokens, sign-in names, the configured tenant and the home
+/// directory from a report.
+fn redact(report: &str, config: &Config) -> String {
+    let jwt = Regex::new(r"eyJ[A-Za-z0-9_-]*\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*").unwrap();
+    let email =
+        Regex::new(r"([A-Za-z0-9])[A-Za-z0-9._%+-]*@[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)*").unwrap();
+    let mut report = jwt.replace_all(report, "[token]").into_owned();
+    // The authority usually names the tenant, so it goes first.
+    for (value, placeholder) in [
+        (&config.authority, "[authority]"),
+        (&config.tenant, "[tenant]"),
+    ] {
+        if let Some(value) = value.as_deref().filter(|value| !value.is_empty()) {
+            report = report.replace(value, placeholder);
+        }
+    }
+    report = email.replace_all(&report, "$1***@***").into_owned();
+    // Longest first, since the app data directories are inside the home directory.
+    for var in ["LOCALAPPDATA", "APPDATA", "USERPROFILE", "HOME"] {
+        if let Ok(dir) = env::var(var) {
+            if !dir.is_empty() {
+                report = report.replace(&dir, &format!("${}", var));
+            }
+        }
+    }
+    report
+}
+
+/// Checks every known token source for `account_types`, and the presence
+/// service hosts they use.
+pub async fn run(
+    config: &Config,
+    config_path: PathBuf,
+    options: &SourceOptions,
+    endpoints: &Endpoints,
+    account_type: Option<AccountType>,
+    bundle: Option<&Path>,
+) -> Result<(), Error> {
+    let now = now();
+    let account_types = match account_type {
+        Some(account_type) => vec![account_type],
+        None => vec![AccountType::Microsoft, AccountType::Live],
+    };
+
+    let mut sections = vec![config_section(config, &config_path)];
+    for app in sources::APPS {
+        let source = match sources::from_name(app, options) {
+            Some(source) => source,
+            None => continue,
+        };
+        let mut checks = Vec::new();
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for &account_type in &account_types {
+            for path in source.paths(account_type) {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        checks.extend(paths.iter().map(|path| check_path(path)));
+        for &account_type in &account_types {
+            let result = source
+                .discover(account_type)
+                .and_then(|_| source.tokens(account_type));
+            checks.push(check_tokens(
+                result,
+                format!("`--account {}` tokens", account_type.name()),
+                now,
+            ));
+        }
+        sections.push(Section {
+            title: format!("{} (--app {})", source.describe(), app),
+            checks,
+        });
+    }
+
+    let mut network = Vec::new();
+    let mut urls: Vec<String> = Vec::new();
+    for &account_type in &account_types {
+        let url = endpoints.presence(account_type);
+        if urls.contains(&url) {
+            continue;
+        }
+        match url.parse::<Uri>() {
+            Ok(uri) if uri.host().is_some() => network.extend(check_host(uri).await),
+            _ => network.push(Check::new(Status::Fail, &url, "not a valid URL")),
+        }
+        urls.push(url);
+    }
+    sections.push(Section {
+        title: "Presence service".to_string(),
+        checks: network,
+    });
+
+    print!("{}", render(&sections, true));
+    let problems = sections
+        .iter()
+        .flat_map(|section| &section.checks)
+        .filter(|check| matches!(check.status, Status::Fail))
+        .count();
+    if problems == 0 {
+        println!("No problems found.");
+    }
+
+    if let Some(bundle) = bundle {
+        let contents = format!(
+            "tpcli {} support bundle\nCreated: {}\nPlatform: {} {}\n\n{}",
+            env!("CARGO_PKG_VERSION"),
+            Utc::now().to_rfc3339(),
+            env::consts::OS,
+            env::consts::ARCH,
+            render(&sections, false)
+        );
+        fs::write(bundle, redact(&contents, config))?;
+        println!(
+            "Wrote support bundle to {} (tokens, sign-in names, tenants and home directories \
+             are redacted).",
+            bundle.display()
+        );
+    }
+    if problems > 0 {
+        return Err(format!("{} problem(s) found", problems).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_identities() {
+        let config = Config {
+            tenant: Some("contoso.onmicrosoft.com".to_string()),
+            authority: Some("https://login.contoso.example/adfs".to_string()),
+            ..Config::default()
+        };
+        let token = crate::jwt::fake(serde_json::json!({ "upn": "alice@contoso.com" }));
+        let report = format!(
+            "tenant: contoso.onmicrosoft.com\n\
+             authority: https://login.contoso.example/adfs\n\
+             tokens: 1 valid (alice.smith@contoso.com, bob@sub.fabrikam.co.uk)\n\
+             token: {}\n",
+            token
+        );
+        assert_eq!(
+            redact(&report, &config),
+            "tenant: [tenant]\n\
+             authority: [authority]\n\
+             tokens: 1 valid (a***@***, b***@***)\n\
+             token: [token]\n"
+        );
+    }
+}
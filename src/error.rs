@@ -0,0 +1,293 @@
+//! Errors that end a `tpcli` run, and the process exit code for each kind, so
+//! scripts can tell them apart:
+//!
+//! | Code | Meaning                                                  |
+//! |------|----------------------------------------------------------|
+//! | 0    | Success                                                  |
+//! | 1    | Any other error, e.g. a file that can't be written       |
+//! | 2    | Invalid command line arguments                           |
+//! | 3    | The config file can't be read or parsed                  |
+//! | 4    | No usable auth token was found                           |
+//! | 5    | Teams couldn't be reached (DNS, connection, TLS), or     |
+//! |      | didn't answer in time                                    |
+//! | 6    | Teams rejected the auth token (HTTP 401 or 403), or the  |
+//! |      | identity provider refused to renew it                    |
+//! | 7    | Teams or the identity provider rejected the request for  |
+//! |      | another reason, or is limiting requests (HTTP 429)       |
+
+use chrono::{DateTime, Utc};
+use colored::*;
+use hyper::{header::RETRY_AFTER, Body, Response, StatusCode};
+use std::{fmt, time::Duration};
+
+use crate::{config::ConfigError, sources::DiscoveryError};
+
+/// Longest part of an error response body to show.
+const MAX_BODY_LEN: usize = 300;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Rejected by the argument parser, which formats its own message.
+    Args(clap::Error),
+    /// An argument value that couldn't be parsed, e.g. `--in 5 parsecs`.
+    Usage(String),
+    Config(ConfigError),
+    NoToken(DiscoveryError),
+    /// The request never got a response.
+    Network(hyper::Error),
+    /// No response came within the request timeout.
+    Timeout(Duration),
+    /// Teams refused the token.
+    Unauthorized {
+        status: StatusCode,
+        body: String,
+    },
+    /// Teams refused the request.
+    Api {
+        status: StatusCode,
+        body: String,
+    },
+    /// Teams is limiting requests, and may say when to try again.
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Exit code for this kind of error; see the module docs.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Args(_) | Error::Usage(_) => 2,
+            Error::Config(_) => 3,
+            Error::NoToken(_) => 4,
+            Error::Network(_) | Error::Timeout(_) => 5,
+            Error::Unauthorized { .. } => 6,
+            Error::Api { .. } | Error::RateLimited { .. } => 7,
+        }
+    }
+
+    /// Whether the same request may succeed later: the network failed or
+    /// timed out, Teams had a server error, or it is limiting requests.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::Timeout(_) | Error::RateLimited { .. } => true,
+            Error::Api { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// What the user can do about it, if there's anything to suggest.
+    fn hint(&self) -> Option<String> {
+        match self {
+            Error::Args(_) | Error::Usage(_) | Error::Other(_) => None,
+            Error::Config(_) => Some(
+                "fix the file, or point `--config` or `TPCLI_CONFIG` at another one".to_string(),
+            ),
+            Error::NoToken(DiscoveryError::Ambiguous(_)) => {
+                Some("`tpcli accounts` lists the accounts tpcli can find".to_string())
+            }
+            Error::NoToken(DiscoveryError::NotFound(_)) => Some(
+                "sign in to Teams in the app or a browser, or run `tpcli login`; \
+                 `tpcli doctor` shows where tpcli looked"
+                    .to_string(),
+            ),
+            Error::Network(_) => Some(
+                "check your internet connection and proxy; \
+                 `tpcli doctor` tests the connection to Teams"
+                    .to_string(),
+            ),
+            Error::Timeout(_) => Some(
+                "check your internet connection, or raise `request_timeout` in the config \
+                 if it is just slow"
+                    .to_string(),
+            ),
+            Error::Unauthorized { .. } => Some(
+                "the token may have expired or been revoked; sign in to Teams again, \
+                 and use `tpcli token show` to see which token was used"
+                    .to_string(),
+            ),
+            Error::Api { status, .. } if status.is_server_error() => {
+                Some("Teams is having problems; try again later".to_string())
+            }
+            Error::Api { .. } => None,
+            Error::RateLimited { .. } => Some("wait a while before trying again".to_string()),
+        }
+    }
+
+    /// Prints the error and its hint to stderr.
+    pub fn report(&self) {
+        if let Error::Args(err) = self {
+            eprintln!("{}", err.message);
+            return;
+        }
+        eprintln!("{} {}", "error:".red().bold(), self);
+        if let Some(hint) = self.hint() {
+            eprintln!("{} {}", "hint:".yellow().bold(), hint);
+        }
+    }
+
+    /// The error for a response Teams answered with `status`.
+    fn rejected(status: StatusCode, body: &[u8]) -> Self {
+        let mut body = String::from_utf8_lossy(body).trim().to_string();
+        if body.len() > MAX_BODY_LEN {
+            let mut end = MAX_BODY_LEN;
+            while !body.is_char_boundary(end) {
+                end -= 1;
+            }
+            body.truncate(end);
+            body.push_str("...");
+        }
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Error::Unauthorized { status, body }
+            }
+            _ => Error::Api { status, body },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, status, body) = match self {
+            Error::Args(err) => return write!(f, "{}", err),
+            Error::Usage(message) => return write!(f, "{}", message),
+            Error::Config(err) => return write!(f, "{}", err),
+            Error::NoToken(err) => return write!(f, "{}", err),
+            Error::Network(err) => return write!(f, "Failed to reach Teams: {}", err),
+            Error::Timeout(timeout) => {
+                return write!(
+                    f,
+                    "Teams didn't answer within {}",
+                    humantime::format_duration(*timeout)
+                )
+            }
+            Error::Other(err) => return write!(f, "{}", err),
+            Error::RateLimited {
+                retry_after: Some(retry_after),
+            } => {
+                return write!(
+                    f,
+                    "Teams is limiting requests; retry in {}",
+                    humantime::format_duration(*retry_after)
+                )
+            }
+            Error::RateLimited { retry_after: None } => {
+                return write!(f, "Teams is limiting requests")
+            }
+            Error::Unauthorized { status, body } => ("the auth token", status, body),
+            Error::Api { status, body } => ("the request", status, body),
+        };
+        write!(f, "Teams rejected {} (HTTP {})", what, status.as_u16())?;
+        if !body.is_empty() {
+            write!(f, ": {}", body)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<clap::Error> for Error {
+    fn from(err: clap::Error) -> Self {
+        Error::Args(err)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::Config(err)
+    }
+}
+
+impl From<DiscoveryError> for Error {
+    fn from(err: DiscoveryError) -> Self {
+        Error::NoToken(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::Network(err)
+    }
+}
+
+impl From<hyper::http::Error> for Error {
+    fn from(err: hyper::http::Error) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message.into())
+    }
+}
+
+/// Sorts the boxed errors the rest of the crate returns into their kind.
+impl From<Box<dyn std::error::Error + Send + Sync>> for Error {
+    fn from(err: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<DiscoveryError>() {
+            Ok(err) => return Error::NoToken(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ConfigError>() {
+            Ok(err) => return Error::Config(*err),
+            Err(err) => err,
+        };
+        match err.downcast::<hyper::Error>() {
+            Ok(err) => Error::Network(*err),
+            Err(err) => Error::Other(err),
+        }
+    }
+}
+
+/// How long a `Retry-After` header says to wait: a number of seconds, or an
+/// HTTP date.
+fn retry_after(resp: &Response<Body>) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Passes a successful response through, and turns any other into an error
+/// carrying the start of its body.
+pub async fn check_status(resp: Response<Body>) -> Result<Response<Body>, Error> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::RateLimited {
+            retry_after: retry_after(&resp),
+        });
+    }
+    let body = hyper::body::to_bytes(resp.into_body())
+        .await
+        .unwrap_or_default();
+    Err(Error::rejected(status, &body))
+}
@@ -0,0 +1,202 @@
+//! Microsoft Graph requests, and the Graph presence backend.
+//!
+//! The Graph backend sets presence with the documented presence API instead
+//! of the Teams presence service. Statuses set with `setUserPreferredPresence`
+//! are the user's preferred presence, as if chosen in Teams, and expire after
+//! `expirationDuration`.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{Body, Method, Request, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    cloud::Endpoints,
+    error::Error,
+    http::HttpClient,
+    jwt::{Audience, Jwt},
+    refresh::TokenManager,
+    Presence,
+};
+
+/// App ID of Microsoft Graph, which v1 tokens name as their audience.
+const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
+
+#[derive(Deserialize, Debug)]
+struct GraphUser {
+    id: String,
+}
+
+/// The object ID of the user with this sign-in name or email address.
+/// `graph_url` is the base URL of Graph, without the API version.
+pub async fn user_id(
+    client: &HttpClient,
+    graph_url: &str,
+    token: &str,
+    user: &str,
+) -> Result<String, Error> {
+    let resp = client
+        .send(|| {
+            Ok(Request::builder()
+                .method(Method::GET)
+                .uri(format!(
+                    "{}/v1.0/users/{}?$select=id",
+                    graph_url,
+                    urlencoding::encode(user)
+                ))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())?)
+        })
+        .await
+        .map_err(|err| match err {
+            Error::Api { status, .. } if status == StatusCode::NOT_FOUND => {
+                Error::Usage(format!("No user `{}` found", user))
+            }
+            err => err,
+        })?;
+    let resp_body = hyper::body::to_bytes(resp.into_body()).await?;
+    Ok(serde_json::from_slice::<GraphUser>(&resp_body)?.id)
+}
+
+/// Whether `token` was issued for Graph rather than the presence service.
+fn is_graph_token(token: &str, endpoints: &Endpoints) -> bool {
+    let resource = endpoints.graph_scope();
+    let resource = resource.trim_end_matches("/.default");
+    let is_graph = |audience: &String| {
+        let audience = audience.trim_end_matches('/');
+        audience == GRAPH_APP_ID || audience.eq_ignore_ascii_case(resource)
+    };
+    let claims = Jwt {
+        token: token.to_string(),
+    }
+    .claims();
+    match claims.ok().and_then(|claims| claims.aud) {
+        Some(Audience::One(audience)) => is_graph(&audience),
+        Some(Audience::Many(audiences)) => audiences.iter().any(is_graph),
+        None => false,
+    }
+}
+
+/// A Graph token: the token found, if it is one, or else one redeemed with
+/// its refresh token.
+pub async fn token(tokens: &mut TokenManager<'_>, endpoints: &Endpoints) -> Result<String, Error> {
+    let (token, _) = tokens.token().await?;
+    if is_graph_token(&token, endpoints) {
+        return Ok(token);
+    }
+    tokens.token_for_scope(&endpoints.graph_scope()).await
+}
+
+/// `duration` as an ISO 8601 duration to the nearest second, e.g. `PT1H30M`.
+fn iso_duration(duration: chrono::Duration) -> String {
+    let secs = ((duration.num_milliseconds() + 500) / 1000).max(0);
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let mut iso = "PT".to_string();
+    if hours > 0 {
+        iso.push_str(&format!("{}H", hours));
+    }
+    if minutes > 0 {
+        iso.push_str(&format!("{}M", minutes));
+    }
+    if secs > 0 || iso == "PT" {
+        iso.push_str(&format!("{}S", secs));
+    }
+    iso
+}
+
+async fn post(
+    client: &HttpClient,
+    graph_url: &str,
+    token: &str,
+    path: &str,
+    body: Value,
+) -> Result<(), Error> {
+    let body = body.to_string();
+    client
+        .send(|| {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(format!("{}/v1.0/{}", graph_url, path))
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.clone()))?)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Sets the signed-in user's preferred presence until `expiration`, or for
+/// as long as Graph keeps it by default. [`Presence::Reset`] clears it.
+pub async fn set_availability(
+    client: &HttpClient,
+    graph_url: &str,
+    token: &str,
+    presence: &Presence,
+    expiration: Option<DateTime<Utc>>,
+) -> Result<(), Error> {
+    if let Presence::Reset = presence {
+        return post(
+            client,
+            graph_url,
+            token,
+            "me/presence/clearUserPreferredPresence",
+            json!({}),
+        )
+        .await;
+    }
+
+    let mut body = json!({
+        "availability": presence,
+        "activity": match presence {
+            Presence::Offline => "OffWork".to_string(),
+            _ => serde_json::to_value(presence)?
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        },
+    });
+    if let Some(expiration) = expiration {
+        body["expirationDuration"] = json!(iso_duration(expiration - Utc::now()));
+    }
+    post(
+        client,
+        graph_url,
+        token,
+        "me/presence/setUserPreferredPresence",
+        body,
+    )
+    .await
+}
+
+/// Sets the signed-in user's status message until `expiration`. No message
+/// clears it.
+pub async fn set_message(
+    client: &HttpClient,
+    graph_url: &str,
+    token: &str,
+    message: Option<&str>,
+    expiration: Option<DateTime<Utc>>,
+) -> Result<(), Error> {
+    let mut status_message = json!({
+        "message": {
+            "content": message.unwrap_or_default(),
+            "contentType": "text",
+        },
+    });
+    if let Some(expiration) = expiration {
+        status_message["expiryDateTime"] = json!({
+            "dateTime": expiration
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .trim_end_matches('Z'),
+            "timeZone": "UTC",
+        });
+    }
+    post(
+        client,
+        graph_url,
+        token,
+        "me/presence/setStatusMessage",
+        json!({ "statusMessage": status_message }),
+    )
+    .await
+}
@@ -0,0 +1,264 @@
+//! The HTTP client every request goes through, with timeouts and retries.
+//!
+//! Requests that fail in a way that may not last (connection errors,
+//! timeouts, server errors and rate limiting) are retried after an
+//! exponentially growing, jittered delay, or after as long as a `Retry-After`
+//! header asks. Retrying stops after a number of retries or once a time limit
+//! has passed, whichever comes first.
+
+use hyper::{client::HttpConnector, Body, Client, Request, Response};
+use hyper_tls::HttpsConnector;
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::Config,
+    error::{check_status, Error},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RETRY_TIME_LIMIT: Duration = Duration::from_secs(60);
+
+/// How long to wait for requests, and how to retry them.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How long to wait for each response.
+    pub timeout: Duration,
+    /// How many times to retry a request after the first attempt.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub delay: Duration,
+    /// Longest to keep trying, from the first attempt.
+    pub time_limit: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            delay: DEFAULT_RETRY_DELAY,
+            time_limit: DEFAULT_RETRY_TIME_LIMIT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The policy the config sets, with defaults for what it leaves out.
+    pub fn new(config: &Config) -> Self {
+        RetryPolicy {
+            timeout: config.request_timeout.unwrap_or(DEFAULT_TIMEOUT),
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            delay: config.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY),
+            time_limit: config.retry_time_limit.unwrap_or(DEFAULT_RETRY_TIME_LIMIT),
+        }
+    }
+}
+
+/// `delay` with up to half of it taken off at random, to the millisecond, so
+/// clients that failed together don't all retry together.
+fn jitter(delay: Duration) -> Duration {
+    let millis = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+    Duration::from_millis(millis - fastrand::u64(0..=millis / 2))
+}
+
+pub struct HttpClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    pub policy: RetryPolicy,
+}
+
+impl HttpClient {
+    pub fn new(policy: RetryPolicy) -> Self {
+        HttpClient {
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            policy,
+        }
+    }
+
+    /// Sends `request` once, waiting at most `timeout` for the whole
+    /// response. The body is read in full, so a server that stalls part way
+    /// through it times out too.
+    async fn attempt(
+        &self,
+        request: Request<Body>,
+        timeout: Duration,
+    ) -> Result<Response<Body>, Error> {
+        let response = async {
+            let (parts, body) = self.client.request(request).await?.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            Ok::<_, hyper::Error>(Response::from_parts(parts, Body::from(body)))
+        };
+        match tokio::time::timeout(timeout, response).await {
+            Ok(resp) => Ok(resp?),
+            Err(_) => Err(Error::Timeout(timeout)),
+        }
+    }
+
+    /// Sends `request` once, with the request timeout but no retries, for
+    /// requests that can't safely be repeated.
+    pub async fn request(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+        self.attempt(request, self.policy.timeout).await
+    }
+
+    /// Sends the request `build` makes, and again for each retry, until it
+    /// gets a successful response or an error worth giving up on. Errors are
+    /// the response's, as [`check_status`] returns them.
+    pub async fn send<F>(&self, build: F) -> Result<Response<Body>, Error>
+    where
+        F: Fn() -> Result<Request<Body>, Error>,
+    {
+        let policy = &self.policy;
+        let start = Instant::now();
+        let mut delay = policy.delay;
+        let mut retries = 0;
+        loop {
+            // Every attempt counts towards the time limit, not just the waits.
+            let remaining = policy.time_limit.saturating_sub(start.elapsed());
+            let timeout = policy
+                .timeout
+                .min(Duration::from_millis(remaining.as_millis() as u64));
+            let err = match self.attempt(build()?, timeout).await {
+                Ok(resp) => match check_status(resp).await {
+                    Ok(resp) => return Ok(resp),
+                    Err(err) => err,
+                },
+                Err(err) => err,
+            };
+            if !err.is_transient() || retries >= policy.retries {
+                return Err(err);
+            }
+
+            let (wait, reason) = match err {
+                // The error already says how long to wait.
+                Error::RateLimited {
+                    retry_after: Some(retry_after),
+                } => (retry_after, "Teams is limiting requests".to_string()),
+                _ => (jitter(delay), err.to_string()),
+            };
+            if start.elapsed().saturating_add(wait) >= policy.time_limit {
+                return Err(err);
+            }
+            eprintln!(
+                "{}; retrying in {}",
+                reason,
+                humantime::format_duration(wait)
+            );
+            tokio::time::sleep(wait).await;
+            // Any longer would be past the time limit anyway.
+            delay = delay.saturating_mul(2).min(policy.time_limit);
+            retries += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{body::Bytes, Method, StatusCode};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_server::{self, TestServer};
+
+    fn client(timeout: Duration, retries: u32, time_limit: Duration) -> HttpClient {
+        HttpClient::new(RetryPolicy {
+            timeout,
+            retries,
+            delay: Duration::from_millis(10),
+            time_limit,
+        })
+    }
+
+    async fn get(client: &HttpClient, server: &TestServer) -> Result<Response<Body>, Error> {
+        client
+            .send(|| {
+                Ok(Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("{}/v1/me", server.url))
+                    .body(Body::empty())?)
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let calls = AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            Some(match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => test_server::json(503, json!({})),
+                _ => test_server::json(200, json!({ "ok": true })),
+            })
+        });
+        let client = client(Duration::from_secs(5), 3, Duration::from_secs(10));
+
+        let resp = get(&client, &server).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"ok":true}"#);
+        assert_eq!(server.received().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn waits_out_retry_after() {
+        let calls = AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            Some(match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header("Retry-After", "1")
+                    .body(Body::empty())
+                    .unwrap(),
+                _ => test_server::json(200, json!({})),
+            })
+        });
+        let client = client(Duration::from_secs(5), 3, Duration::from_secs(10));
+
+        get(&client, &server).await.unwrap();
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        // Not the 10ms retry delay.
+        assert!(received[1].at - received[0].at >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn stops_before_long_waits() {
+        let server = TestServer::start(|_| Some(test_server::json(503, json!({}))));
+        let client = HttpClient::new(RetryPolicy {
+            timeout: Duration::from_secs(5),
+            retries: u32::MAX,
+            delay: Duration::MAX,
+            time_limit: Duration::from_secs(10),
+        });
+
+        let err = get(&client, &server).await.unwrap_err();
+        assert!(matches!(err, Error::Api { .. }), "{:?}", err);
+        assert_eq!(server.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_the_time_limit() {
+        let server = TestServer::start(|_| None);
+        let client = client(Duration::from_millis(200), 10, Duration::from_millis(500));
+
+        let start = Instant::now();
+        let err = get(&client, &server).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{:?}", err);
+        assert!(start.elapsed() < Duration::from_millis(900));
+        let attempts = server.received().len();
+        assert!((2..=4).contains(&attempts), "{} attempts", attempts);
+    }
+
+    #[tokio::test]
+    async fn times_out_stalled_bodies() {
+        let server = TestServer::start(|_| {
+            let stalled = futures::stream::pending::<Result<Bytes, std::io::Error>>();
+            Some(Response::new(Body::wrap_stream(stalled)))
+        });
+        let client = client(Duration::from_millis(100), 1, Duration::from_secs(10));
+
+        let err = get(&client, &server).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{:?}", err);
+        assert_eq!(server.received().len(), 2);
+    }
+}
@@ -0,0 +1,197 @@
+//! Reading the claims of the JWTs Azure AD and the Skype token service issue.
+//!
+//! Signatures are not checked: `tpcli` only looks at its own tokens, to learn
+//! when they expire and whose they are.
+
+use serde::Deserialize;
+use std::fmt;
+
+use crate::AccountType;
+
+/// Tenant Azure AD issues personal Microsoft account tokens from.
+const CONSUMER_TENANT_ID: &str = "9188040d-6c67-4c5b-b112-36a304b66dad";
+
+pub struct Jwt {
+    pub token: String,
+}
+
+#[derive(Debug)]
+pub enum JwtError {
+    /// The token doesn't have the three dot separated parts of a JWT.
+    Malformed,
+    /// The payload isn't base64url.
+    Base64(base64::DecodeError),
+    /// The payload isn't a JSON object of claims.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::Malformed => write!(f, "not a JWT"),
+            JwtError::Base64(err) => write!(f, "payload is not base64url: {}", err),
+            JwtError::Json(err) => write!(f, "payload is not valid claims: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for JwtError {}
+
+/// The `aud` claim, which may be one audience or several.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl fmt::Display for Audience {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Audience::One(audience) => write!(f, "{}", audience),
+            Audience::Many(audiences) => write!(f, "{}", audiences.join(", ")),
+        }
+    }
+}
+
+/// The registered claims, and those Azure AD and Skype tokens use to say
+/// whose token it is and what it's for. Every claim is optional.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Claims {
+    /// Resource the token was issued for.
+    pub aud: Option<Audience>,
+    /// Issuer, which names the tenant for Azure AD tokens.
+    pub iss: Option<String>,
+    /// Issue time, in seconds since the epoch.
+    pub iat: Option<u64>,
+    /// Time the token becomes valid, in seconds since the epoch.
+    pub nbf: Option<u64>,
+    /// Expiry, in seconds since the epoch.
+    pub exp: Option<u64>,
+    /// Tenant ID.
+    pub tid: Option<String>,
+    /// Object ID of the user in the tenant.
+    pub oid: Option<String>,
+    /// Sign-in name of work and school accounts, e.g. `alice@contoso.com`.
+    pub upn: Option<String>,
+    /// Sign-in name in v1 tokens without a `upn`, e.g. for guests.
+    pub unique_name: Option<String>,
+    /// Sign-in name in v2 tokens, including personal accounts.
+    pub preferred_username: Option<String>,
+    /// Display name of the user.
+    pub name: Option<String>,
+    /// Client the token was issued to, in v1 tokens.
+    pub appid: Option<String>,
+    /// Client the token was issued to, in v2 tokens.
+    pub azp: Option<String>,
+    /// Space separated delegated scopes.
+    pub scp: Option<String>,
+    /// Skype identity, only present in Skype tokens, e.g. `live:alice`.
+    pub skypeid: Option<String>,
+}
+
+impl Claims {
+    /// The kind of account the token belongs to, or `None` if the claims
+    /// don't say.
+    pub fn account_type(&self) -> Option<AccountType> {
+        if let Some(skypeid) = &self.skypeid {
+            if skypeid.starts_with("live:") {
+                return Some(AccountType::Live);
+            }
+            if skypeid.starts_with("orgid:") {
+                return Some(AccountType::Microsoft);
+            }
+        }
+        let consumer = self.tid.as_deref() == Some(CONSUMER_TENANT_ID)
+            || self
+                .iss
+                .as_deref()
+                .is_some_and(|iss| iss.contains(CONSUMER_TENANT_ID));
+        if consumer {
+            Some(AccountType::Live)
+        } else if self.tid.is_some() {
+            Some(AccountType::Microsoft)
+        } else {
+            None
+        }
+    }
+
+    /// The user's sign-in name, if the token carries one.
+    pub fn user(&self) -> Option<&str> {
+        self.upn
+            .as_deref()
+            .or(self.unique_name.as_deref())
+            .or(self.preferred_username.as_deref())
+    }
+
+    /// The client the token was issued to.
+    pub fn client_id(&self) -> Option<&str> {
+        self.appid.as_deref().or(self.azp.as_deref())
+    }
+}
+
+impl Jwt {
+    /// Decodes the payload. JWTs use unpadded base64url; padding is tolerated
+    /// since some token caches add it back.
+    pub fn claims(&self) -> Result<Claims, JwtError> {
+        let mut parts = self.token.trim().split('.');
+        let payload = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(_header), Some(payload), Some(_signature), None) => payload,
+            _ => return Err(JwtError::Malformed),
+        };
+        let decoded_payload =
+            base64::decode_config(payload.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                .map_err(JwtError::Base64)?;
+        serde_json::from_slice(&decoded_payload).map_err(JwtError::Json)
+    }
+
+    /// Expiry of the token in seconds since the epoch, or `None` if the token
+    /// is not a JWT with an `exp` claim.
+    pub fn exp(&self) -> Option<u64> {
+        self.claims().ok()?.exp
+    }
+}
+
+/// An unsigned JWT with `claims`, for tests.
+#[cfg(test)]
+pub fn fake(claims: serde_json::Value) -> String {
+    let encode =
+        |json: serde_json::Value| base64::encode_config(json.to_string(), base64::URL_SAFE_NO_PAD);
+    format!(
+        "{}.{}.sig",
+        encode(serde_json::json!({ "alg": "none" })),
+        encode(claims)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(claims: serde_json::Value) -> Claims {
+        serde_json::from_value(claims).unwrap()
+    }
+
+    #[test]
+    fn detects_account_type() {
+        let account_type = |json| claims(json).account_type();
+        assert_eq!(
+            account_type(serde_json::json!({ "skypeid": "orgid:0b4e0e63" })),
+            Some(AccountType::Microsoft)
+        );
+        assert_eq!(
+            account_type(serde_json::json!({ "skypeid": "live:alice" })),
+            Some(AccountType::Live)
+        );
+        assert_eq!(
+            account_type(serde_json::json!({ "tid": CONSUMER_TENANT_ID })),
+            Some(AccountType::Live)
+        );
+        assert_eq!(
+            account_type(serde_json::json!({ "tid": "72f988bf-86f1-41af-91ab-2d7cd011db47" })),
+            Some(AccountType::Microsoft)
+        );
+        assert_eq!(account_type(serde_json::json!({})), None);
+    }
+}
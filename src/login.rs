@@ -0,0 +1,358 @@
+//! `tpcli login`: the OAuth 2.0 device authorization grant against Azure AD.
+//!
+//! The user is shown a code to enter at the identity provider's device login
+//! page while we poll the token endpoint. The resulting access and refresh
+//! tokens are stored in `credentials.json` in the config directory and used
+//! by the `login` token source.
+
+use colored::*;
+use hyper::{body, Body, Method, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Write, path::PathBuf, time::Duration};
+
+use crate::{
+    cloud::Endpoints,
+    config::{config_dir, Config},
+    error::Error,
+    http::{HttpClient, RetryPolicy},
+    now, AccountType,
+};
+
+/// The Microsoft Teams desktop client, which may request presence tokens.
+pub const DEFAULT_CLIENT_ID: &str = "1fec8e78-bce4-4aaf-ab1b-5451cc387264";
+const DEFAULT_TENANT: &str = "organizations";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// How much longer to wait between polls each time the server says to slow
+/// down, per RFC 8628.
+const SLOW_DOWN_STEP: Duration = Duration::from_secs(5);
+
+/// Tokens saved by `tpcli login`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredLogin {
+    pub authority: String,
+    pub tenant: String,
+    pub client_id: String,
+    pub scope: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Seconds since the epoch.
+    pub expires_at: u64,
+}
+
+impl StoredLogin {
+    pub fn token_url(&self) -> String {
+        format!("{}/{}/oauth2/v2.0/token", self.authority, self.tenant)
+    }
+
+    pub fn path() -> PathBuf {
+        config_dir().join("credentials.json")
+    }
+
+    /// The stored login, if `tpcli login` has been run.
+    pub fn load() -> Result<Option<StoredLogin>, Error> {
+        let path = StoredLogin::path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = StoredLogin::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// POSTs a form to an OAuth endpoint, returning the status and body.
+pub async fn post_form(
+    client: &HttpClient,
+    url: &str,
+    form: &[(&str, &str)],
+    origin: Option<&str>,
+) -> Result<(u16, body::Bytes), Error> {
+    let form_body = form
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut builder = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("Content-Type", "application/x-www-form-urlencoded");
+    if let Some(origin) = origin {
+        builder = builder.header("Origin", origin);
+    }
+    let resp = client.request(builder.body(Body::from(form_body))?).await?;
+    let status = resp.status().as_u16();
+    Ok((status, body::to_bytes(resp.into_body()).await?))
+}
+
+/// Turns an OAuth error response into an error of the matching kind: a
+/// refused grant or client means the credentials are no good, as when Teams
+/// rejects a token, and throttling and server errors may pass.
+pub fn oauth_error(status: u16, body: &[u8]) -> Error {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+    let (code, message) = match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(err) => {
+            let message = format!(
+                "{}: {}",
+                err.error,
+                err.error_description.unwrap_or_default()
+            );
+            (Some(err.error), message)
+        }
+        Err(_) => (
+            None,
+            format!("identity provider returned HTTP {}", status.as_u16()),
+        ),
+    };
+    match code.as_deref() {
+        _ if status == StatusCode::TOO_MANY_REQUESTS => Error::RateLimited { retry_after: None },
+        Some(
+            "invalid_grant" | "invalid_client" | "unauthorized_client" | "interaction_required",
+        ) => Error::Unauthorized {
+            status,
+            body: message,
+        },
+        _ if status.is_server_error() => Error::Api {
+            status,
+            body: message,
+        },
+        _ => Error::Other(message.into()),
+    }
+}
+
+/// Polls the token endpoint every `interval` until the user has signed in,
+/// backing off when asked to, or until `deadline` in seconds since the epoch.
+async fn poll_token(
+    client: &HttpClient,
+    token_url: &str,
+    client_id: &str,
+    device_code: &str,
+    mut interval: Duration,
+    deadline: u64,
+) -> Result<TokenResponse, Error> {
+    loop {
+        if now() >= deadline {
+            return Err("The device code expired before sign-in was completed"
+                .to_string()
+                .into());
+        }
+        tokio::time::sleep(interval).await;
+
+        let (status, resp_body) = post_form(
+            client,
+            token_url,
+            &[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("client_id", client_id),
+                ("device_code", device_code),
+            ],
+            None,
+        )
+        .await?;
+        if status == 200 {
+            return Ok(serde_json::from_slice(&resp_body)?);
+        }
+        match serde_json::from_slice::<ErrorResponse>(&resp_body) {
+            Ok(err) if err.error == "authorization_pending" => {}
+            Ok(err) if err.error == "slow_down" => interval += SLOW_DOWN_STEP,
+            _ => return Err(oauth_error(status, &resp_body)),
+        }
+    }
+}
+
+/// Runs the device code flow and stores the tokens. `authority` overrides
+/// the identity provider of `endpoints`.
+pub async fn login(
+    config: &Config,
+    endpoints: &Endpoints,
+    authority: Option<&str>,
+) -> Result<(), Error> {
+    let authority = authority
+        .map(String::from)
+        .unwrap_or_else(|| endpoints.authority());
+    let authority = authority.trim_end_matches('/');
+    let scope = format!(
+        "{}.default offline_access",
+        endpoints.audience(AccountType::Microsoft)
+    );
+    let tenant = config.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let client_id = config.client_id.as_deref().unwrap_or(DEFAULT_CLIENT_ID);
+
+    let client = HttpClient::new(RetryPolicy::new(config));
+
+    let (status, resp_body) = post_form(
+        &client,
+        &format!("{}/{}/oauth2/v2.0/devicecode", authority, tenant),
+        &[("client_id", client_id), ("scope", &scope)],
+        None,
+    )
+    .await?;
+    if status != 200 {
+        return Err(oauth_error(status, &resp_body));
+    }
+    let device_code: DeviceCodeResponse = serde_json::from_slice(&resp_body)?;
+
+    println!(
+        "To sign in, open {} and enter the code {}",
+        device_code.verification_uri.cyan(),
+        device_code.user_code.green()
+    );
+
+    let token = poll_token(
+        &client,
+        &format!("{}/{}/oauth2/v2.0/token", authority, tenant),
+        client_id,
+        &device_code.device_code,
+        Duration::from_secs(device_code.interval.unwrap_or(5)),
+        now() + device_code.expires_in,
+    )
+    .await?;
+
+    let stored = StoredLogin {
+        authority: authority.to_string(),
+        tenant: tenant.to_string(),
+        client_id: client_id.to_string(),
+        scope,
+        access_token: token.access_token,
+        refresh_token: token.refresh_token,
+        expires_at: now() + token.expires_in,
+    };
+    stored.save()?;
+
+    println!(
+        "Signed in. Tokens saved to {}",
+        StoredLogin::path().display().to_string().purple()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_server::{self, TestServer};
+
+    #[tokio::test]
+    async fn polls_until_signed_in() {
+        let polls = AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            Some(match polls.fetch_add(1, Ordering::SeqCst) {
+                0 => test_server::json(400, json!({ "error": "authorization_pending" })),
+                1 => test_server::json(400, json!({ "error": "slow_down" })),
+                _ => test_server::json(
+                    200,
+                    json!({ "access_token": "access", "refresh_token": "refresh", "expires_in": 3600 }),
+                ),
+            })
+        });
+        let client = HttpClient::new(RetryPolicy::default());
+
+        let token = poll_token(
+            &client,
+            &format!("{}/organizations/oauth2/v2.0/token", server.url),
+            "client",
+            "device",
+            Duration::from_millis(10),
+            now() + 60,
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.access_token, "access");
+        assert_eq!(token.refresh_token.as_deref(), Some("refresh"));
+
+        let received = server.received();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].method, Method::POST);
+        assert_eq!(received[0].path, "/organizations/oauth2/v2.0/token");
+        assert_eq!(
+            received[0].headers["Content-Type"],
+            "application/x-www-form-urlencoded"
+        );
+        assert!(received[0].body.contains("device_code=device"));
+        assert!(received[0]
+            .body
+            .contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code"));
+        // `slow_down` adds five seconds to the interval.
+        assert!(received[2].at - received[1].at >= SLOW_DOWN_STEP);
+    }
+
+    #[tokio::test]
+    async fn stops_on_other_errors() {
+        let server = TestServer::start(|_| {
+            Some(test_server::json(
+                400,
+                json!({ "error": "expired_token", "error_description": "too late" }),
+            ))
+        });
+        let client = HttpClient::new(RetryPolicy::default());
+
+        let err = poll_token(
+            &client,
+            &format!("{}/organizations/oauth2/v2.0/token", server.url),
+            "client",
+            "device",
+            Duration::from_millis(10),
+            now() + 60,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "expired_token: too late");
+        assert_eq!(server.received().len(), 1);
+    }
+
+    #[test]
+    fn sorts_oauth_errors() {
+        let error = |status, error: &str| {
+            let body = json!({ "error": error, "error_description": "AADSTS" }).to_string();
+            oauth_error(status, body.as_bytes())
+        };
+        assert!(matches!(
+            error(400, "invalid_grant"),
+            Error::Unauthorized { .. }
+        ));
+        assert_eq!(error(400, "invalid_grant").exit_code(), 6);
+        assert!(matches!(error(429, "throttled"), Error::RateLimited { .. }));
+        assert!(error(503, "temporarily_unavailable").is_transient());
+        assert!(matches!(error(400, "invalid_scope"), Error::Other(_)));
+        assert!(oauth_error(502, b"<html>").is_transient());
+    }
+}
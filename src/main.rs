@@ -1,291 +1,50 @@
-use base64::decode;
-use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
-use clap::{crate_version, App, Arg};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use clap::{crate_version, App, AppSettings, Arg, ArgGroup, SubCommand};
 use colored::*;
-use db_key::Key;
-use fs_extra::dir::{copy as copy_dir, CopyOptions};
-use futures;
 use humantime::parse_duration;
-use hyper::{client::HttpConnector, Body, Client, Method, Request};
-use hyper_tls::HttpsConnector;
-use leveldb::{
-    database::Database,
-    iterator::Iterable,
-    options::{Options, ReadOptions},
-};
-use rusqlite::{Connection, Result};
+use hyper::{Body, Method};
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
-use std::{cmp::Reverse, str::FromStr, time::SystemTime};
+use serde_json::json;
+use std::str::FromStr;
 use std::{
-    env, fs,
-    io::{stdin, stdout, Write},
-    path::PathBuf,
-    str,
+    fmt,
+    io::{stdin, stdout, Read, Write},
+    path::Path,
+    process,
+    sync::Arc,
 };
-use tempfile::tempdir;
-
-/// Used for keying leveldb.
-#[derive(Debug, PartialEq)]
-pub struct BytesKey {
-    key: Vec<u8>,
-}
-
-impl Key for BytesKey {
-    fn from_u8(key: &[u8]) -> Self {
-        Self { key: key.to_vec() }
-    }
-
-    fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
-        f(self.key.as_slice())
-    }
-}
 
-#[derive(Deserialize, Debug)]
-struct PresenceToken {
-    token: String,
-    expiration: u64,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct SkypeToken {
-    skype_token: String,
-    expiration: u64,
-}
-
-struct Jwt {
-    token: String,
-}
-
-impl Jwt {
-    fn exp(&self) -> u64 {
-        let payload = self.token.split('.').nth(1).unwrap();
-        let decoded_payload = decode(payload).unwrap();
-        let decoded_payload = str::from_utf8(&decoded_payload).unwrap();
-        let payload_object: serde_json::Value = serde_json::from_str(decoded_payload).unwrap();
-        let exp = payload_object["exp"].as_u64().unwrap();
-        exp
-        // let now = SystemTime::now()
-        //     .duration_since(SystemTime::UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_secs();
-        // let exp = exp - now;
-        // println!("Token expires in {} seconds", exp);
-    }
-}
-
-// enum Browser {
-//     Chrome,
-//     Teams,
-// }
-
-// enum PathType {
-//     Leveldb,
-//     Sqlite,
-// }
-
-fn teams_sqlite_path(partition: bool) -> PathBuf {
-    let path = if cfg!(target_os = "macos") {
-        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
-        home.join("Library")
-            .join("Application Support")
-            .join("Microsoft")
-            .join("Teams")
-    } else if cfg!(target_os = "windows") {
-        let app_data = PathBuf::from(env::var("APPDATA").expect("APPDATA env var not found"));
-        app_data.join("Microsoft").join("Teams")
-    } else if cfg!(target_os = "linux") {
-        if partition {
-            panic!("`--account live` using the Teams app is not supported on linux. Please use Chrome instead.");
-        }
-        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
-        home.join(".config")
-            .join("Microsoft")
-            .join("Microsoft Teams")
-    } else {
-        panic!("Unsupported platform")
-    };
-
-    let path = if partition && cfg!(target_os = "macos") {
-        path.join("Partitions").join("msa")
-    } else {
-        path
-    };
-
-    path.join("Cookies")
-}
-
-fn chrome_leveldb_path() -> PathBuf {
-    if cfg!(target_os = "macos") {
-        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
-        home.join("Library")
-            .join("Application Support")
-            .join("Google")
-            .join("Chrome")
-            .join("Default")
-            .join("Local Storage")
-            .join("leveldb")
-    } else if cfg!(target_os = "windows") {
-        let local_app_data = PathBuf::from(env::var("LOCALAPPDATA").expect("LOCALAPPDATA env var not found"));
-        // panic!("Haven't implemented chrome leveldb path for windows yet")
-        local_app_data
-            .join("Google")
-            .join("Chrome")
-            .join("User Data")
-            .join("Default")
-            .join("Local Storage")
-            .join("leveldb")
-    } else if cfg!(target_os = "linux") {
-        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
-        home.join(".config")
-            .join("google-chrome")
-            .join("Default")
-            .join("Local Storage")
-            .join("leveldb")
-    } else {
-        panic!("Unsupported platform")
-    }
-}
-
-// #[derive(Debug)]
-// enum Error {
-//     PresenceTokenNotFound,
-// }
-
-fn get_leveldb_tokens() -> (Option<PresenceToken>, Option<SkypeToken>) {
-    let leveldb_path = chrome_leveldb_path();
-    let temp_db_dir = tempdir().unwrap();
-    let options = CopyOptions::new();
-    copy_dir(leveldb_path, &temp_db_dir.path(), &options)
-        .expect("Error copying leveldb to temp dir");
-
-    let leveldb_path = temp_db_dir.path().join("leveldb");
-    let lock_file = leveldb_path.join("LOCK");
-    if lock_file.exists() {
-        fs::remove_file(&lock_file).expect("Failed to delete leveldb lock file");
-    }
-
-    let options = Options::new();
-    let database = Database::<BytesKey>::open(&leveldb_path, options)
-        .expect("Failed to open leveldb database");
-
-    let cur_epoch = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    let iter_read_opts = ReadOptions::new();
-    // let mut tokens: Vec<SkypeToken> = database
-    //     .iter(iter_read_opts)
-    //     .map(|(k, v)| {
-    //         // println!("{:?}", k);
-    //         (String::from_utf8(k.key).unwrap_or(String::from("")), v)
-    //     })
-    //     .filter(|(k, _)| {
-    //         println!("{:?}", k);
-    //         k.contains("auth.skype.token")
-    //         // k.starts_with("_https://teams.microsoft.com\u{0}\u{1}ts.")
-    //         //     && k.ends_with(".cache.token.https://presence.teams.microsoft.com/")
-    //     })
-    //     .map(|(_, v)| -> SkypeToken {
-    //         println!("{}", String::from_utf8(v.clone()).unwrap());
-    //         serde_json::from_slice(&v[1..]).expect("Failed to parse presence token info")
-    //     })
-    //     .filter(|token_info| token_info.expiration > cur_epoch)
-    //     .collect();
-
-    let mut skype_tokens: Vec<SkypeToken> = Vec::default();
-    let mut presence_tokens: Vec<PresenceToken> = Vec::default();
-    for (key, value) in database.iter(iter_read_opts) {
-        let key = String::from_utf8(key.key).unwrap_or(String::from(""));
-        // let value2 = String::from_utf8(value.clone()).unwrap_or(String::from(""));
-        // println!("{}: {}", key, value2);
-        if key.ends_with("auth.skype.token") {
-            let new_skype_token: SkypeToken =
-                serde_json::from_slice(&value[1..]).expect("Failed to parse skype token info");
-            // println!("Skype token hit: {:?}", &new_skype_token);
-            if new_skype_token.expiration > cur_epoch {
-                skype_tokens.push(new_skype_token)
-            }
-        } else if key.ends_with(".cache.token.https://presence.teams.microsoft.com/") {
-            let new_presence_token: PresenceToken =
-                serde_json::from_slice(&value[1..]).expect("Failed to parse presence token info");
-            if new_presence_token.expiration > cur_epoch {
-                presence_tokens.push(new_presence_token)
-            }
-        }
-    }
-
-    skype_tokens.sort_by_key(|token| Reverse(token.expiration));
-    presence_tokens.sort_by_key(|token| Reverse(token.expiration));
-
-    // println!("{:?}", skype_tokens);
-    // println!("{:?}", presence_tokens);
-
-    (
-        presence_tokens.into_iter().next(),
-        skype_tokens.into_iter().next(),
-    )
-
-    // if tokens.iter().count() >= 1 {
-    //     // Ok(tokens.remove(0))
-    //     Err(Error::PresenceTokenNotFound)
-    // } else {
-    //     Err(Error::PresenceTokenNotFound)
-    // }
-    // Err(Error::PresenceTokenNotFound)
-}
-
-fn get_sqlite_tokens() -> Jwt {
-    let sqlite_path = teams_sqlite_path(true);
-    let conn = Connection::open(sqlite_path).unwrap();
-    let mut stmt = conn
-        .prepare("select value from cookies where name = 'skypetoken_asm'")
-        .unwrap();
-    let mut tokens: Vec<Jwt> = stmt
-        .query_map([], |row| Ok(row.get_unwrap(0)))
-        .unwrap()
-        .map(|res| Jwt {
-            token: res.unwrap(),
-        })
-        .collect();
-
-    tokens.sort_by_key(|token| Reverse(token.exp()));
-    assert!(tokens.len() >= 1, "No tokens found in MS Teams cookie db");
-
-    tokens.remove(0)
-}
-
-fn decode_urlenc(s: String) -> String {
-    urlencoding::decode(&s).unwrap().into_owned()
-}
-
-fn get_auth_sqlite_tokens() -> Jwt {
-    let sqlite_path = teams_sqlite_path(false);
-    let conn = Connection::open(sqlite_path).unwrap();
-    let mut stmt = conn
-        .prepare("select value from cookies where name = 'authtoken'")
-        .unwrap();
-    let mut tokens: Vec<Jwt> = stmt
-        .query_map([], |row| Ok(row.get_unwrap(0)))
-        .unwrap()
-        .map(|res| {
-            let raw_token_info = res.unwrap();
-            let token_info = decode_urlenc(raw_token_info);
-            let bearer_pair = token_info.split("&").next().unwrap();
-            Jwt {
-                token: bearer_pair.split("=").last().unwrap().to_string(),
-            }
-        })
-        .collect();
-
-    tokens.sort_by_key(|token| Reverse(token.exp()));
-    assert!(tokens.len() >= 1, "No tokens found in MS Teams cookie db");
-
-    tokens.remove(0)
-}
-
-#[derive(Debug, Serialize)]
+mod accounts;
+mod cache;
+mod cloud;
+mod config;
+mod doctor;
+mod error;
+mod graph;
+mod http;
+mod jwt;
+mod login;
+mod output;
+mod presence;
+mod refresh;
+mod sources;
+#[cfg(test)]
+mod test_server;
+mod token;
+mod trouter;
+mod watch;
+mod who;
+
+use cache::TokenCache;
+use cloud::{Cloud, Endpoints};
+use config::Config;
+use error::Error;
+use http::{HttpClient, RetryPolicy};
+use login::StoredLogin;
+use refresh::TokenManager;
+use sources::{AccountFilter, ExplicitSource, SourceOptions, TokenInput, TokenSource};
+
+#[derive(Debug, PartialEq, Serialize)]
 enum Presence {
     Available,
     Busy,
@@ -313,9 +72,9 @@ impl FromStr for Presence {
     }
 }
 
-impl ToString for Presence {
-    fn to_string(&self) -> String {
-        String::from_str(match self {
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             Presence::Available => "available",
             Presence::Busy => "busy",
             Presence::DoNotDisturb => "do_not_disturb",
@@ -324,7 +83,6 @@ impl ToString for Presence {
             Presence::Offline => "offline",
             Presence::Reset => "reset",
         })
-        .unwrap()
     }
 }
 
@@ -368,19 +126,75 @@ impl Serialize for Availability<'_> {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum AccountType {
     Microsoft,
     Live,
 }
 
+/// Which API `tpcli <status>` sets presence with.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// The Teams presence service, as the Teams clients use it.
+    #[default]
+    Teams,
+    /// The Microsoft Graph presence API.
+    Graph,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "teams" => Ok(Backend::Teams),
+            "graph" => Ok(Backend::Graph),
+            _ => Err(format!("Invalid backend `{}`", s)),
+        }
+    }
+}
+
+/// The current time in seconds since the epoch, the unit token expiries are
+/// given in.
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl AccountType {
+    /// The `--account` value for this account type.
+    fn name(self) -> &'static str {
+        match self {
+            AccountType::Microsoft => "ms",
+            AccountType::Live => "live",
+        }
+    }
+}
+
+/// Parses an `--at` time, e.g. `8/5/2021 5:00 PM +00:00`.
+fn parse_at(date_time: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_str(date_time, "%m/%d/%Y %I:%M %p %:z")
+        .map(DateTime::from)
+        .map_err(|err| {
+            Error::Usage(format!(
+                "Invalid `--at` date and time (expected e.g. \"8/5/2021 5:00 PM +00:00\"): {}",
+                err
+            ))
+        })
+}
+
 async fn set_availability(
-    client: &Client<HttpsConnector<HttpConnector>>,
+    client: &HttpClient,
+    endpoints: &Endpoints,
     token: &str,
     account_type: AccountType,
     presence: &Presence,
     expiration: Option<DateTime<Utc>>,
-) -> Result<(), hyper::http::Error> {
+) -> Result<(), Error> {
     let availability = Availability {
         availability: presence,
         activity: match presence {
@@ -392,73 +206,41 @@ async fn set_availability(
 
     let request_body = match presence {
         &Presence::Reset => "".to_string(),
-        _ => serde_json::to_string(&availability).unwrap(),
+        _ => serde_json::to_string(&availability)?,
     };
 
-    let mut builder = Request::builder()
-        .method(Method::PUT)
-        .uri(format!(
-            "https://presence.teams.{}.com/v1/me/forceavailability/",
-            match account_type {
-                AccountType::Microsoft => "microsoft",
-                AccountType::Live => "live",
+    client
+        .send(|| {
+            let mut builder = presence::request(
+                endpoints,
+                account_type,
+                token,
+                Method::PUT,
+                "v1/me/forceavailability/",
+            );
+
+            if !request_body.is_empty() {
+                builder = builder.header("Content-Type", "application/json");
+            } else {
+                builder = builder.header("Content-Length", "0");
             }
-        ))
-        .header("x-ms-client-consumer-type", "teams4life");
-
-    match account_type {
-        AccountType::Microsoft => {
-            builder = builder.header("Authorization", format!("Bearer {}", token));
-        }
-        AccountType::Live => {
-            builder = builder.header("x-skypetoken", token);
-        }
-    }
-
-    if request_body != "" {
-        builder = builder.header("Content-Type", "application/json");
-    } else {
-        builder = builder.header("Content-Length", "0");
-    }
-    let request = builder.body(Body::from(request_body))?;
-
-    let resp = client.request(request).await.unwrap();
-    assert_eq!(resp.status(), 200);
-    Ok::<(), hyper::http::Error>(())
+            Ok(builder.body(Body::from(request_body.clone()))?)
+        })
+        .await?;
+    Ok(())
 }
 
 async fn set_message(
-    client: &Client<HttpsConnector<HttpConnector>>,
+    client: &HttpClient,
+    endpoints: &Endpoints,
     token: &str,
     account_type: AccountType,
     message: Option<&str>,
     pin: bool,
     expiration: Option<DateTime<Utc>>,
-) -> Result<(), hyper::http::Error> {
-    let mut builder = Request::builder()
-        .method(Method::PUT)
-        .uri(format!(
-            "https://presence.teams.{}.com/v1/me/publishnote",
-            match account_type {
-                AccountType::Microsoft => "microsoft",
-                AccountType::Live => "live",
-            }
-        ))
-        .header("x-ms-client-consumer-type", "teams4life")
-        .header("Content-Type", "application/json");
-
-    match account_type {
-        AccountType::Microsoft => {
-            builder = builder.header("Authorization", format!("Bearer {}", token));
-        }
-        AccountType::Live => {
-            builder = builder.header("x-skypetoken", token);
-        }
-    }
-
-    let request = builder.body(Body::from(format!(
-        "{{\"message\":\"{}\",\"expiry\":\"{}\"}}",
-        match message {
+) -> Result<(), Error> {
+    let request_body = json!({
+        "message": match message {
             Some(message) => format!(
                 "{}{}",
                 message,
@@ -466,83 +248,210 @@ async fn set_message(
             ),
             None => "".to_string(),
         },
-        match expiration {
+        "expiry": match expiration {
             Some(expiration) => expiration.to_rfc3339_opts(SecondsFormat::Millis, true),
             None => "9999-12-31T05:00:00.000Z".to_string(),
-        }
-    )))?;
-
-    let resp = client.request(request).await.unwrap();
-    assert_eq!(resp.status(), 200);
-    Ok::<(), hyper::http::Error>(())
-}
-
-#[derive(Clone, Copy)]
-enum InstanceType {
-    TeamsApp,
-    Chrome,
+        },
+    })
+    .to_string();
+
+    client
+        .send(|| {
+            Ok(presence::request(
+                endpoints,
+                account_type,
+                token,
+                Method::PUT,
+                "v1/me/publishnote",
+            )
+            .header("Content-Type", "application/json")
+            .body(Body::from(request_body.clone()))?)
+        })
+        .await?;
+    Ok(())
 }
 
 async fn set_both(
-    instance_type: InstanceType,
-    account_type: AccountType,
+    tokens: &mut TokenManager<'_>,
+    endpoints: &Endpoints,
+    backend: Backend,
     presence: &Presence,
     expiration: Option<DateTime<Utc>>,
     message: Option<&str>,
     pin: bool,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let token: String;
-    match instance_type {
-        InstanceType::TeamsApp => {
-            match account_type {
-                AccountType::Microsoft => {
-                    // let (presence_token, _) = get_leveldb_tokens();
-                    let auth_token = get_auth_sqlite_tokens();
-                    token = auth_token.token;
-                    // panic!("non-live account Teams app not supported yet");
-                }
-                AccountType::Live => {
-                    let skype_token = get_sqlite_tokens();
-                    token = skype_token.token;
-                }
-            }
-        }
-        InstanceType::Chrome => {
-            let (presence_token, skype_token) = get_leveldb_tokens();
-            match account_type {
-                AccountType::Microsoft => {
-                    token = presence_token.expect("Missing presence token").token;
-                }
-                AccountType::Live => {
-                    token = skype_token.expect("Missing skype token").skype_token;
-                }
-            }
+) -> Result<(), Error> {
+    if backend == Backend::Graph {
+        if pin {
+            return Err(Error::Usage(
+                "`--pin` isn't supported by the Graph backend".to_string(),
+            ));
         }
+        let token = graph::token(tokens, endpoints)
+            .await
+            .map_err(|err| match err {
+                Error::Other(err) => Error::from(format!(
+                    "The Graph backend needs a Graph token, or one that can be renewed for \
+                     Graph ({}); sign in with `tpcli login`",
+                    err
+                )),
+                err => err,
+            })?;
+        let graph_url = endpoints.graph();
+        let client = tokens.client();
+        let _ = futures::try_join!(
+            graph::set_availability(client, &graph_url, &token, presence, expiration),
+            graph::set_message(client, &graph_url, &token, message, expiration)
+        )?;
+        return Ok(());
     }
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    let (token, account_type) = tokens.token().await?;
+    let client = tokens.client();
 
     let _ = futures::try_join!(
-        set_availability(&client, &token, account_type, &presence, expiration),
-        set_message(&client, &token, account_type, message, pin, expiration)
+        set_availability(
+            client,
+            endpoints,
+            &token,
+            account_type,
+            presence,
+            expiration
+        ),
+        set_message(
+            client,
+            endpoints,
+            &token,
+            account_type,
+            message,
+            pin,
+            expiration
+        )
     )?;
     Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn main() {
+    #[cfg(target_os = "windows")]
+    ansi_term::enable_ansi_support();
+
+    if let Err(err) = run().await {
+        err.report();
+        process::exit(err.exit_code());
+    }
+}
+
+async fn run() -> Result<(), Error> {
     // for person in person_iter {
     //     println!("Found person {:?}", person.unwrap());
     // }
 
-    #[cfg(target_os = "windows")]
-    ansi_term::enable_ansi_support();
-
-    // std::process::exit(0);
-    let matches = App::new("tpcli (Teams Presence CLI)")
+    let matches = match App::new("tpcli (Teams Presence CLI)")
         .version(crate_version!())
         .about("Easily control your Microsoft Teams presence with this CLI program")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("login")
+                .about("Sign in with a device code, for machines without a logged-in Teams app or browser")
+                .arg(
+                    Arg::with_name("authority")
+                        .long("--authority")
+                        .takes_value(true)
+                        .help("Identity provider to sign in with. Defaults to the one of the cloud, e.g. https://login.microsoftonline.com"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("accounts")
+                .about("List every account a token can be found for, with its tenant and expiry"),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check the token sources and the connection to Teams, and explain what is wrong")
+                .arg(
+                    Arg::with_name("bundle")
+                        .long("--bundle")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Also write the report, with tokens, sign-in names and home directories redacted, to FILE"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Show your current status and message"),
+        )
+        .subcommand(
+            SubCommand::with_name("who")
+                .about("Show the status and message of other users")
+                .arg(
+                    Arg::with_name("users")
+                        .required(true)
+                        .multiple(true)
+                        .help("Users by sign-in name, object ID, Skype ID (live:...) or MRI (8:...)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print changes to the status of other users as they happen")
+                .arg(
+                    Arg::with_name("users")
+                        .required(true)
+                        .multiple(true)
+                        .help("Users by sign-in name, object ID, Skype ID (live:...) or MRI (8:...)"),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("--until")
+                        .takes_value(true)
+                        .possible_values(&[
+                            "available",
+                            "busy",
+                            "do_not_disturb",
+                            "be_right_back",
+                            "away",
+                            "offline",
+                        ])
+                        .help("Exit once every user has this status"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("--interval")
+                        .takes_value(true)
+                        .default_value("30s")
+                        .help("How often to check, or to check again while reconnecting with --push"),
+                )
+                .arg(
+                    Arg::with_name("push")
+                        .long("--push")
+                        .help("Have changes pushed over the Teams notification channel instead of checking"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("events")
+                .about("Print status changes for you and other users as JSON lines, as Teams pushes them")
+                .arg(
+                    Arg::with_name("users")
+                        .multiple(true)
+                        .help("Users by sign-in name, object ID, Skype ID (live:...) or MRI (8:...)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("token")
+                .about("Inspect or export the auth token tpcli would use")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show where the token was found, whose it is and when it expires")
+                        .arg(
+                            Arg::with_name("reveal")
+                                .long("--reveal")
+                                .help("Print the token itself instead of redacting it"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Print the raw token, renewing it first if it is about to expire"),
+                ),
+        )
         .arg(
             Arg::with_name("status")
                 .possible_values(&[
@@ -561,13 +470,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             Arg::with_name("application-type")
                 // .short("m")
                 .long("--app")
-                .possible_values(&[
-                    "chrome",
-                    "teams",
-                ])
+                .possible_values(sources::APPS)
                 .default_value("teams")
                 .takes_value(true)
-                .help("Application to get authentication token from (Google Chrome or Microsoft Teams app)"),
+                .multiple(true)
+                .use_delimiter(true)
+                .require_delimiter(true)
+                .global(true)
+                .help("Applications to get authentication token from, tried in order (e.g. teams,chrome). Chromium-based browsers include Snap and Flatpak installs"),
         )
         .arg(
             Arg::with_name("account-type")
@@ -577,9 +487,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                     "live",
                     "ms",
                 ])
-                .default_value("ms")
                 .takes_value(true)
-                .help("Type of Teams account you have: microsoft.com or live.com (personal account)"),
+                .global(true)
+                .help("Type of Teams account you have: microsoft.com or live.com (personal account). Detected from the token when not given"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("--profile")
+                .takes_value(true)
+                .global(true)
+                .help("Browser profile to read the token from, by directory or display name (e.g. \"Profile 1\"). Searches every profile by default. Also applies the profile's settings from the config"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("--user")
+                .takes_value(true)
+                .global(true)
+                .help("Use the token of this user, by sign-in name or object ID, when several accounts are signed in"),
+        )
+        .arg(
+            Arg::with_name("tenant")
+                .long("--tenant")
+                .takes_value(true)
+                .global(true)
+                .help("Use a token from this tenant, by ID or domain. Overrides `tenant` in the config"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("--backend")
+                .possible_values(&["teams", "graph"])
+                .takes_value(true)
+                .global(true)
+                .help("API to set your status with: the Teams presence service, or Microsoft Graph. Overrides `backend` in the config [default: teams]"),
+        )
+        .arg(
+            Arg::with_name("cloud")
+                .long("--cloud")
+                .takes_value(true)
+                .env("TPCLI_CLOUD")
+                .global(true)
+                .help("Cloud your account is in: commercial, live, gcc, gcch, dod, or the base URL of a server standing in for every service. Overrides `cloud` in the config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("--config")
+                .takes_value(true)
+                .global(true)
+                .help("Path to the config file (defaults to config.toml in the tpcli config directory)"),
         )
         .arg(
             Arg::with_name("message")
@@ -609,47 +563,249 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 // .short("a")
                 .long("--at")
                 .takes_value(true)
-                .conflicts_with("time-duration")
                 .help("Reset status and message at this time"),
         )
-        .get_matches();
+        .group(ArgGroup::with_name("expiration").args(&["time-duration", "expiration-time"]))
+        .arg(
+            Arg::with_name("token")
+                .long("--token")
+                .takes_value(true)
+                .env("TPCLI_TOKEN")
+                .hide_env_values(true)
+                .global(true)
+                .conflicts_with_all(&["token-file", "token-stdin"])
+                .help("Auth token to use instead of reading one from an application"),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .long("--token-file")
+                .takes_value(true)
+                .global(true)
+                .conflicts_with("token-stdin")
+                .help("Read the auth token from this file, re-reading it whenever it changes"),
+        )
+        .arg(
+            Arg::with_name("token-stdin")
+                .long("--token-stdin")
+                .takes_value(false)
+                .requires("expiration")
+                .help("Read the auth token from stdin (requires --in or --at)"),
+        )
+        .get_matches_safe()
+    {
+        Ok(matches) => matches,
+        Err(err) => match err.kind {
+            // Help and version go to stdout and exit successfully.
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => err.exit(),
+            _ => return Err(err.into()),
+        },
+    };
+
+    let mut config = Config::load(matches.value_of("config"), matches.value_of("profile"))?;
+    if let Some(tenant) = matches.value_of("tenant") {
+        config.tenant = Some(tenant.to_string());
+    }
+    if let Some(cloud) = matches.value_of("cloud") {
+        config.cloud = Some(Cloud::from_str(cloud).map_err(Error::Usage)?);
+    }
+    if let Some(backend) = matches.value_of("backend") {
+        config.backend = Some(Backend::from_str(backend).map_err(Error::Usage)?);
+    }
+    let backend = config.backend.unwrap_or_default();
+    let cloud = config.cloud.clone().unwrap_or_default();
+    let endpoints = Endpoints::new(cloud.clone(), &config);
+    let retry_policy = RetryPolicy::new(&config);
+
+    if let Some(login_matches) = matches.subcommand_matches("login") {
+        return login::login(&config, &endpoints, login_matches.value_of("authority")).await;
+    }
 
     let expiration_date_time: Option<DateTime<Utc>> = match matches.value_of("time-duration") {
         Some(duration) => {
             let now = Utc::now();
-            let parsed_duration =
-                parse_duration(duration).expect("Failed to parse `--in` arg duration");
-            Some(now + Duration::from_std(parsed_duration).unwrap())
+            let parsed_duration = parse_duration(duration)
+                .map_err(|err| Error::Usage(format!("Invalid `--in` duration: {}", err)))?;
+            let parsed_duration = Duration::from_std(parsed_duration)
+                .map_err(|_| Error::Usage("`--in` duration is too long".to_string()))?;
+            Some(now + parsed_duration)
         }
         None => match matches.value_of("expiration-time") {
-            Some(date_time_str) => {
-                // DateTime::parse_from_str("8/5/1994 8:00 AM +00:00", "%m/%d/%Y %H:%M %p %:z")?;
-                Some(DateTime::from(
-                    DateTime::parse_from_str(date_time_str, "%m/%d/%Y %H:%M %p %:z")
-                        .expect("Failed to parse `--at` date and time"),
-                ))
-            }
+            Some(date_time_str) => Some(parse_at(date_time_str)?),
             None => None,
         },
     };
 
-    let account_type = match matches.value_of("account-type").unwrap() {
-        "live" => AccountType::Live,
-        "ms" => AccountType::Microsoft,
-        _ => panic!("Invalid account type"),
+    let account_type = match matches.value_of("account-type") {
+        Some("live") => Some(AccountType::Live),
+        Some("ms") => Some(AccountType::Microsoft),
+        Some(other) => return Err(Error::Usage(format!("Invalid account type `{}`", other))),
+        None => cloud.account_type(),
+    };
+    let source_options = SourceOptions {
+        profile: matches.value_of("profile").map(String::from),
+        cookie_password: config.cookie_password(),
+        token_helper: config.token_helper.clone(),
+        tenant: config.tenant.clone(),
+        token_cache: config
+            .token_cache
+            .unwrap_or(true)
+            .then(|| Arc::new(TokenCache::new(&config))),
+        cloud,
+    };
+
+    if matches.subcommand_matches("accounts").is_some() {
+        return accounts::list(&source_options);
+    }
+
+    if let Some(doctor_matches) = matches.subcommand_matches("doctor") {
+        // Read the sources themselves, not what the cache remembers of them.
+        let options = SourceOptions {
+            token_cache: None,
+            ..source_options
+        };
+        return doctor::run(
+            &config,
+            Config::path(matches.value_of("config")),
+            &options,
+            &endpoints,
+            account_type,
+            doctor_matches.value_of("bundle").map(Path::new),
+        )
+        .await;
+    }
+
+    let token_input = if let Some(token) = matches.value_of("token") {
+        let origin = if matches.occurrences_of("token") > 0 {
+            "--token"
+        } else {
+            "TPCLI_TOKEN"
+        };
+        Some(TokenInput::Value(token.to_string(), origin))
+    } else if let Some(path) = matches.value_of("token-file") {
+        Some(TokenInput::File(path.into()))
+    } else if matches.is_present("token-stdin") {
+        let mut token = String::new();
+        stdin().read_to_string(&mut token)?;
+        Some(TokenInput::Value(token, "--token-stdin"))
+    } else {
+        None
+    };
+    let token_sources: Vec<Box<dyn TokenSource>> = match token_input {
+        Some(input) => vec![Box::new(ExplicitSource::new(input))],
+        None => {
+            let mut apps: Vec<&str> = matches
+                .values_of("application-type")
+                .into_iter()
+                .flatten()
+                .collect();
+            // A configured helper and a stored login are tried first unless
+            // `--app` says otherwise.
+            if matches.occurrences_of("application-type") == 0 {
+                if StoredLogin::path().is_file() {
+                    apps.insert(0, "login");
+                }
+                if config.token_helper.is_some() {
+                    apps.insert(0, "helper");
+                }
+            }
+            apps.into_iter()
+                .map(|app| {
+                    sources::from_name(app, &source_options).ok_or_else(|| {
+                        Error::Usage(format!(
+                            "`--app {}` needs `token_helper` set in the config",
+                            app
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        }
     };
-    let instance_type = match matches.value_of("application-type").unwrap() {
-        "chrome" => InstanceType::Chrome,
-        "teams" => InstanceType::TeamsApp,
-        _ => panic!("Invalid application type"),
+    let account_filter = AccountFilter {
+        user: matches.value_of("user").map(String::from),
+        tenant: config.tenant.clone(),
     };
-    let presence_to_set = Presence::from_str(matches.value_of("status").unwrap()).unwrap();
+
+    if let Some(token_matches) = matches.subcommand_matches("token") {
+        return match token_matches.subcommand() {
+            ("show", Some(show_matches)) => token::show(
+                &token_sources,
+                account_type,
+                &account_filter,
+                show_matches.is_present("reveal"),
+            ),
+            ("export", _) => {
+                let mut tokens =
+                    TokenManager::new(&token_sources, account_type, account_filter, retry_policy);
+                token::export(&mut tokens).await
+            }
+            _ => unreachable!("clap requires a `token` subcommand"),
+        };
+    }
+
+    if matches.subcommand_matches("get").is_some() {
+        let mut tokens =
+            TokenManager::new(&token_sources, account_type, account_filter, retry_policy);
+        return presence::get(&mut tokens, &endpoints).await;
+    }
+
+    if let Some(who_matches) = matches.subcommand_matches("who") {
+        let users: Vec<&str> = who_matches
+            .values_of("users")
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut tokens =
+            TokenManager::new(&token_sources, account_type, account_filter, retry_policy);
+        return who::who(&mut tokens, &endpoints, &users).await;
+    }
+
+    if let Some(watch_matches) = matches.subcommand_matches("watch") {
+        let users: Vec<&str> = watch_matches
+            .values_of("users")
+            .into_iter()
+            .flatten()
+            .collect();
+        let interval = parse_duration(watch_matches.value_of("interval").unwrap_or("30s"))
+            .map_err(|err| Error::Usage(format!("Invalid `--interval`: {}", err)))?;
+        let until = watch_matches
+            .value_of("until")
+            .map(Presence::from_str)
+            .transpose()
+            .map_err(|err| Error::Usage(err.to_string()))?;
+        let mut tokens =
+            TokenManager::new(&token_sources, account_type, account_filter, retry_policy);
+        return watch::watch(
+            &mut tokens,
+            &endpoints,
+            &users,
+            interval,
+            until,
+            watch_matches.is_present("push"),
+        )
+        .await;
+    }
+
+    if let Some(events_matches) = matches.subcommand_matches("events") {
+        let users: Vec<&str> = events_matches
+            .values_of("users")
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut tokens =
+            TokenManager::new(&token_sources, account_type, account_filter, retry_policy);
+        return trouter::events(&mut tokens, &endpoints, &users).await;
+    }
+
+    let presence_to_set = Presence::from_str(matches.value_of("status").unwrap_or_default())
+        .map_err(|err| Error::Usage(err.to_string()))?;
 
     // let default_path = get_teams_db_path();
 
+    let mut tokens = TokenManager::new(&token_sources, account_type, account_filter, retry_policy);
     set_both(
-        instance_type,
-        account_type,
+        &mut tokens,
+        &endpoints,
+        backend,
         &presence_to_set,
         expiration_date_time,
         matches.value_of("message"),
@@ -665,13 +821,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             None => "".to_string(),
         },
         match expiration_date_time {
-            Some(expiration) => format!(
-                ", expiring at {}",
-                DateTime::<Local>::from(expiration)
-                    .format("%m/%d/%Y %I:%M %p")
-                    .to_string()
-                    .purple()
-            ),
+            Some(expiration) =>
+                format!(", expiring at {}", output::local_time(expiration).purple()),
             None => "".to_string(),
         }
     );
@@ -689,8 +840,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     // let (presence_token, skype_token) = get_leveldb_tokens(&default_path);
     set_both(
-        instance_type,
-        account_type,
+        &mut tokens,
+        &endpoints,
+        backend,
         &Presence::Reset,
         None,
         None,
@@ -702,3 +854,177 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_server::{self, TestServer};
+
+    fn explicit_sources(claims: Value) -> Vec<Box<dyn TokenSource>> {
+        let token = jwt::fake(claims);
+        vec![Box::new(ExplicitSource::new(TokenInput::Value(
+            token, "--token",
+        )))]
+    }
+
+    #[test]
+    fn parses_at_times() {
+        assert_eq!(
+            parse_at("8/5/2021 5:00 PM +00:00").unwrap().to_rfc3339(),
+            "2021-08-05T17:00:00+00:00"
+        );
+        assert_eq!(
+            parse_at("08/05/2021 12:30 AM -07:00").unwrap().to_rfc3339(),
+            "2021-08-05T07:30:00+00:00"
+        );
+        assert!(parse_at("8/5/2021 17:00 PM +00:00").is_err());
+    }
+
+    #[tokio::test]
+    async fn sets_presence_with_graph() {
+        let server = TestServer::start(|_| Some(test_server::json(200, json!({}))));
+        let config = Config {
+            graph_url: Some(format!("{}/", server.url)),
+            ..Config::default()
+        };
+        let endpoints = Endpoints::new(Cloud::default(), &config);
+        let sources = explicit_sources(json!({
+            "aud": "https://graph.microsoft.com",
+            "exp": 4102444800u64,
+        }));
+        let mut tokens = TokenManager::new(
+            &sources,
+            Some(AccountType::Microsoft),
+            AccountFilter::default(),
+            RetryPolicy::default(),
+        );
+
+        let expiration = Utc::now() + Duration::hours(1);
+        set_both(
+            &mut tokens,
+            &endpoints,
+            Backend::Graph,
+            &Presence::DoNotDisturb,
+            Some(expiration),
+            Some("say \"hi\""),
+            false,
+        )
+        .await
+        .unwrap();
+        set_both(
+            &mut tokens,
+            &endpoints,
+            Backend::Graph,
+            &Presence::Reset,
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let mut received = server.received();
+        received.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<&str> = received.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/v1.0/me/presence/clearUserPreferredPresence",
+                "/v1.0/me/presence/setStatusMessage",
+                "/v1.0/me/presence/setStatusMessage",
+                "/v1.0/me/presence/setUserPreferredPresence",
+            ]
+        );
+        for request in &received {
+            assert_eq!(request.method, Method::POST);
+            assert!(request.headers["Authorization"]
+                .to_str()
+                .unwrap()
+                .starts_with("Bearer "));
+        }
+        let body = |i: usize| serde_json::from_str::<Value>(&received[i].body).unwrap();
+
+        assert_eq!(
+            body(3),
+            json!({
+                "availability": "DoNotDisturb",
+                "activity": "DoNotDisturb",
+                "expirationDuration": "PT1H",
+            })
+        );
+        let messages = [body(1), body(2)];
+        let set = messages
+            .iter()
+            .find(|body| body["statusMessage"]["message"]["content"] != "")
+            .unwrap();
+        assert_eq!(set["statusMessage"]["message"]["content"], "say \"hi\"");
+        assert_eq!(set["statusMessage"]["expiryDateTime"]["timeZone"], "UTC");
+        assert!(messages
+            .iter()
+            .any(|body| body["statusMessage"]["expiryDateTime"].is_null()));
+        assert_eq!(body(0), json!({}));
+    }
+
+    #[tokio::test]
+    async fn escapes_messages() {
+        let server = TestServer::start(|_| Some(test_server::json(200, json!({}))));
+        let config = Config {
+            presence_url: Some(server.url.clone()),
+            ..Config::default()
+        };
+        let endpoints = Endpoints::new(Cloud::default(), &config);
+        let client = HttpClient::new(RetryPolicy::default());
+
+        set_message(
+            &client,
+            &endpoints,
+            "token",
+            AccountType::Microsoft,
+            Some("say \"hi\"\\"),
+            true,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let received = server.received();
+        assert_eq!(received[0].path, "/v1/me/publishnote");
+        assert_eq!(
+            serde_json::from_str::<Value>(&received[0].body).unwrap(),
+            json!({
+                "message": "say \"hi\"\\<pinnednote></pinnednote>",
+                "expiry": "9999-12-31T05:00:00.000Z",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_category_of_graph_token_errors() {
+        let sources = explicit_sources(json!({
+            "aud": "https://api.spaces.skype.com",
+            "exp": 4102444800u64,
+        }));
+        let mut tokens = TokenManager::new(
+            &sources,
+            Some(AccountType::Microsoft),
+            AccountFilter::default(),
+            RetryPolicy::default(),
+        );
+
+        let err = set_both(
+            &mut tokens,
+            &Endpoints::default(),
+            Backend::Graph,
+            &Presence::Busy,
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::Other(_)), "{:?}", err);
+        assert!(err.to_string().contains("tpcli login"));
+    }
+}
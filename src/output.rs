@@ -0,0 +1,76 @@
+//! Formatting shared by the commands that print reports.
+
+use chrono::{DateTime, Local};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How times are shown to the user.
+const LOCAL_TIME_FORMAT: &str = "%m/%d/%Y %I:%M %p";
+
+/// Formats `time` in the local time zone.
+pub fn local_time(time: impl Into<DateTime<Local>>) -> String {
+    time.into().format(LOCAL_TIME_FORMAT).to_string()
+}
+
+/// Formats a time given in seconds since the epoch, like token expiries.
+pub fn epoch_time(secs: u64) -> String {
+    local_time(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// The number of characters `text` takes up on screen, leaving out the
+/// escape sequences `colored` adds.
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// Prints `label: value` lines with the values lined up.
+pub fn print_fields(fields: &[(&str, String)]) {
+    let width = fields
+        .iter()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    for (label, value) in fields {
+        println!("{:width$} {}", format!("{}:", label), value, width = width);
+    }
+}
+
+/// Prints `header` and `rows` as columns padded to their widest cell.
+pub fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let header = header.map(String::from);
+    let mut widths = [0; N];
+    for row in std::iter::once(&header).chain(rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(visible_width(cell));
+        }
+    }
+    for row in std::iter::once(&header).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{}{}", cell, " ".repeat(width - visible_width(cell))))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_colored_text() {
+        assert_eq!(visible_width("\x1b[32mAvailable\x1b[0m"), 9);
+        assert_eq!(visible_width("\x1b[1;36mhi\x1b[0m"), 2);
+        assert_eq!(visible_width("café"), 4);
+    }
+}
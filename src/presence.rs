@@ -0,0 +1,236 @@
+//! Talking to the Teams presence service, and `tpcli get`.
+
+use chrono::{DateTime, Datelike};
+use colored::*;
+use hyper::{http::request::Builder, Body, Method, Request};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cloud::Endpoints,
+    error::Error,
+    http::HttpClient,
+    jwt::Jwt,
+    output::{local_time, print_fields},
+    refresh::TokenManager,
+    AccountType, Presence,
+};
+
+/// The header Teams services take `token` in for `account_type`.
+pub fn auth_header(account_type: AccountType, token: &str) -> (&'static str, String) {
+    match account_type {
+        AccountType::Microsoft => ("Authorization", format!("Bearer {}", token)),
+        AccountType::Live => ("x-skypetoken", token.to_string()),
+    }
+}
+
+/// Starts a request to `path` on the presence service for `account_type`,
+/// authenticated with `token`.
+pub fn request(
+    endpoints: &Endpoints,
+    account_type: AccountType,
+    token: &str,
+    method: Method,
+    path: &str,
+) -> Builder {
+    let (name, value) = auth_header(account_type, token);
+    Request::builder()
+        .method(method)
+        .uri(format!(
+            "{}{}",
+            endpoints.presence(account_type),
+            path.trim_start_matches('/')
+        ))
+        .header("x-ms-client-consumer-type", "teams4life")
+        .header(name, value)
+}
+
+/// The MRI (Teams user ID) of whoever `token` belongs to, e.g.
+/// `8:orgid:<object ID>` or `8:live:alice`.
+pub fn own_mri(token: &str, account_type: AccountType) -> Result<String, Error> {
+    let claims = Jwt {
+        token: token.to_string(),
+    }
+    .claims()
+    .map_err(|err| Error::Other(format!("Can't tell whose the token is: {}", err).into()))?;
+    let mri = match account_type {
+        AccountType::Microsoft => claims.oid.map(|oid| format!("8:orgid:{}", oid)),
+        AccountType::Live => claims.skypeid.map(|skypeid| format!("8:{}", skypeid)),
+    };
+    mri.ok_or_else(|| Error::Other("Can't tell whose the token is: it has no user ID".into()))
+}
+
+/// Presence of one user, as returned by `getpresence`. Fields the service
+/// leaves out are `None`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct UserPresence {
+    pub mri: String,
+    pub presence: PresenceState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PresenceState {
+    /// e.g. `Available`, `BusyIdle` or `PresenceUnknown`.
+    pub availability: Option<String>,
+    /// e.g. `InAMeeting` or `OffWork`.
+    pub activity: Option<String>,
+    pub note: Option<Note>,
+    /// Set by `forceavailability`, i.e. by `tpcli <status>`.
+    pub forced_availability: Option<ForcedAvailability>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Note {
+    pub message: String,
+    pub expiry: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ForcedAvailability {
+    pub availability: Option<String>,
+    #[serde(alias = "desiredExpirationTime")]
+    pub expiry: Option<String>,
+}
+
+/// Marker `tpcli --pin` and Teams append to pinned messages.
+const PINNED_NOTE_MARKER: &str = "<pinnednote></pinnednote>";
+
+impl Note {
+    /// The message without the pinned marker, and whether it was pinned.
+    pub fn text(&self) -> (&str, bool) {
+        match self.message.strip_suffix(PINNED_NOTE_MARKER) {
+            Some(text) => (text, true),
+            None => (&self.message, false),
+        }
+    }
+}
+
+impl Presence {
+    /// The status for an availability the presence service reports. Idle
+    /// variants count as their base status.
+    pub fn from_availability(availability: &str) -> Option<Presence> {
+        match availability {
+            "Available" | "AvailableIdle" => Some(Presence::Available),
+            "Busy" | "BusyIdle" => Some(Presence::Busy),
+            "DoNotDisturb" => Some(Presence::DoNotDisturb),
+            "BeRightBack" => Some(Presence::BeRightBack),
+            "Away" => Some(Presence::Away),
+            "Offline" => Some(Presence::Offline),
+            _ => None,
+        }
+    }
+}
+
+/// An availability as `tpcli` names statuses, colored like
+/// [`Presence::to_string_colored`]. Ones without a `tpcli` name are shown as
+/// the service reports them.
+pub fn availability_colored(availability: &str) -> ColoredString {
+    match Presence::from_availability(availability) {
+        Some(presence) => presence.to_string_colored(),
+        None => availability.normal(),
+    }
+}
+
+/// Formats an expiry the service reports, which is far in the future for
+/// ones that don't expire.
+pub fn format_expiry(expiry: &str) -> String {
+    match DateTime::parse_from_rfc3339(expiry) {
+        Ok(expiry) if expiry.year() >= 9999 => "never".to_string(),
+        Ok(expiry) => local_time(expiry),
+        Err(_) => expiry.to_string(),
+    }
+}
+
+/// Reads the presence of the users with these MRIs.
+pub async fn get_presence(
+    client: &HttpClient,
+    endpoints: &Endpoints,
+    token: &str,
+    account_type: AccountType,
+    mris: &[String],
+) -> Result<Vec<UserPresence>, Error> {
+    let request_body: Vec<_> = mris
+        .iter()
+        .map(|mri| serde_json::json!({ "mri": mri }))
+        .collect();
+    let request_body = serde_json::to_string(&request_body)?;
+
+    let resp = client
+        .send(|| {
+            Ok(request(
+                endpoints,
+                account_type,
+                token,
+                Method::POST,
+                "v1/presence/getpresence/",
+            )
+            .header("Content-Type", "application/json")
+            .body(Body::from(request_body.clone()))?)
+        })
+        .await?;
+    let resp_body = hyper::body::to_bytes(resp.into_body()).await?;
+    Ok(serde_json::from_slice(&resp_body)?)
+}
+
+/// The presence returned for `mri`, if any. MRIs are compared ignoring case,
+/// since the service may return them lowercased.
+pub fn find<'a>(presences: &'a [UserPresence], mri: &str) -> Option<&'a UserPresence> {
+    presences
+        .iter()
+        .find(|presence| presence.mri.eq_ignore_ascii_case(mri))
+}
+
+/// Prints the signed-in user's status and message.
+pub async fn get(tokens: &mut TokenManager<'_>, endpoints: &Endpoints) -> Result<(), Error> {
+    let (token, account_type) = tokens.token().await?;
+    let mri = own_mri(&token, account_type)?;
+    let presences = get_presence(
+        tokens.client(),
+        endpoints,
+        &token,
+        account_type,
+        std::slice::from_ref(&mri),
+    )
+    .await?;
+    let state = &find(&presences, &mri)
+        .ok_or_else(|| Error::Other("Teams didn't return your presence".into()))?
+        .presence;
+
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    if let Some(availability) = &state.availability {
+        fields.push((
+            "Availability",
+            availability_colored(availability).to_string(),
+        ));
+    }
+    if let Some(activity) = &state.activity {
+        fields.push(("Activity", activity.clone()));
+    }
+    if let Some(expiry) = state
+        .forced_availability
+        .as_ref()
+        .and_then(|forced| forced.expiry.as_deref())
+    {
+        fields.push(("Status expires", format_expiry(expiry).purple().to_string()));
+    }
+    match &state.note {
+        Some(note) if !note.message.is_empty() => {
+            let (text, pinned) = note.text();
+            fields.push(("Message", text.cyan().to_string()));
+            fields.push(("Pinned", if pinned { "yes" } else { "no" }.to_string()));
+            if let Some(expiry) = &note.expiry {
+                fields.push((
+                    "Message expires",
+                    format_expiry(expiry).purple().to_string(),
+                ));
+            }
+        }
+        _ => fields.push(("Message", "none".to_string())),
+    }
+
+    print_fields(&fields);
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! Keeping a token valid across long waits.
+//!
+//! [`TokenManager`] hands out the current token, renewing it with its refresh
+//! token shortly before it expires. Tokens that can't be refreshed are
+//! replaced by running token discovery again.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    http::{HttpClient, RetryPolicy},
+    login::{oauth_error, post_form, StoredLogin, TokenResponse},
+    now,
+    sources::{self, AccountFilter, Token, TokenOrigin, TokenSource},
+    AccountType,
+};
+
+/// Renew tokens this many seconds before they expire.
+pub const RENEW_MARGIN_SECS: u64 = 5 * 60;
+
+/// What's needed to redeem a refresh token at an OAuth token endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RefreshGrant {
+    /// e.g. `https://login.microsoftonline.com/<tenant>/oauth2/v2.0/token`
+    pub token_url: String,
+    pub client_id: String,
+    pub refresh_token: String,
+    pub scope: String,
+    /// `Origin` header to send, needed for refresh tokens issued to browser
+    /// (single-page app) clients.
+    pub origin: Option<String>,
+    /// Write renewed tokens back to `tpcli login`'s credentials file.
+    pub save_to_login: bool,
+}
+
+/// Redeems `grant` for a new access token. The returned token carries the
+/// grant forward, with the new refresh token if the server rotated it.
+pub async fn refresh(client: &HttpClient, grant: &RefreshGrant) -> Result<Token, Error> {
+    let (status, resp_body) = post_form(
+        client,
+        &grant.token_url,
+        &[
+            ("grant_type", "refresh_token"),
+            ("client_id", &grant.client_id),
+            ("refresh_token", &grant.refresh_token),
+            ("scope", &grant.scope),
+        ],
+        grant.origin.as_deref(),
+    )
+    .await?;
+    if status != 200 {
+        return Err(oauth_error(status, &resp_body));
+    }
+    let resp: TokenResponse = serde_json::from_slice(&resp_body)?;
+
+    let mut grant = grant.clone();
+    if let Some(refresh_token) = resp.refresh_token {
+        grant.refresh_token = refresh_token;
+    }
+    let token = Token {
+        token: resp.access_token,
+        expiration: now() + resp.expires_in,
+        refresh: Some(grant),
+        origin: TokenOrigin::default(),
+    };
+
+    if token.refresh.as_ref().is_some_and(|g| g.save_to_login) {
+        if let Some(mut stored) = StoredLogin::load()? {
+            stored.access_token = token.token.clone();
+            stored.refresh_token = token.refresh.as_ref().map(|g| g.refresh_token.clone());
+            stored.expires_at = token.expiration;
+            stored.save()?;
+        }
+    }
+
+    Ok(token)
+}
+
+/// Hands out a valid token for an account, renewing or rediscovering it as
+/// needed. Keep one around for as long as tpcli runs.
+pub struct TokenManager<'a> {
+    sources: &'a [Box<dyn TokenSource>],
+    /// `None` until the first token is found, unless given by `--account`.
+    account_type: Option<AccountType>,
+    filter: AccountFilter,
+    client: HttpClient,
+    current: Option<Token>,
+}
+
+impl<'a> TokenManager<'a> {
+    pub fn new(
+        sources: &'a [Box<dyn TokenSource>],
+        account_type: Option<AccountType>,
+        filter: AccountFilter,
+        policy: RetryPolicy,
+    ) -> Self {
+        TokenManager {
+            sources,
+            account_type,
+            filter,
+            client: HttpClient::new(policy),
+            current: None,
+        }
+    }
+
+    /// The HTTP client used for token renewal, shared with presence requests.
+    pub fn client(&self) -> &HttpClient {
+        &self.client
+    }
+
+    /// A token that is valid for at least the next few minutes, and the kind
+    /// of account it belongs to.
+    pub async fn token(&mut self) -> Result<(String, AccountType), Error> {
+        if let (Some(token), Some(account_type)) = (&self.current, self.account_type) {
+            if token.expiration > now() + RENEW_MARGIN_SECS
+                && !self.sources.iter().any(|source| source.changed())
+            {
+                return Ok((token.token.clone(), account_type));
+            }
+        }
+
+        if let (Some(token), Some(account_type)) = (self.current.take(), self.account_type) {
+            if let Some(grant) = &token.refresh {
+                match refresh(&self.client, grant).await {
+                    Ok(token) => return Ok(self.replace(token, account_type)),
+                    Err(err) => eprintln!("Failed to refresh token, searching again: {}", err),
+                }
+            }
+        }
+
+        let (token, account_type) =
+            sources::find_token(self.sources, self.account_type, &self.filter)?;
+        let token = match &token.refresh {
+            Some(grant) if token.expiration <= now() + RENEW_MARGIN_SECS => {
+                refresh(&self.client, grant).await?
+            }
+            _ => token,
+        };
+        Ok(self.replace(token, account_type))
+    }
+
+    /// A token for another resource, e.g. Microsoft Graph, redeemed with the
+    /// refresh token of the current one. Fails if it has no refresh token.
+    pub async fn token_for_scope(&mut self, scope: &str) -> Result<String, Error> {
+        let (_, account_type) = self.token().await?;
+        let grant = match self
+            .current
+            .as_ref()
+            .and_then(|token| token.refresh.clone())
+        {
+            Some(grant) => grant,
+            // Cached tokens leave their refresh token in the source.
+            None => sources::find_token_uncached(self.sources, Some(account_type), &self.filter)?
+                .0
+                .refresh
+                .ok_or_else(|| {
+                    "the token has no refresh token to redeem for other resources".to_string()
+                })?,
+        };
+        // Only the presence token belongs in the credentials file.
+        let grant = RefreshGrant {
+            scope: scope.to_string(),
+            save_to_login: false,
+            ..grant
+        };
+        Ok(refresh(&self.client, &grant).await?.token)
+    }
+
+    /// Keeps `token` for later calls. The account type found first sticks, so
+    /// rediscovery can't switch accounts.
+    fn replace(&mut self, token: Token, account_type: AccountType) -> (String, AccountType) {
+        let secret = token.token.clone();
+        self.current = Some(token);
+        self.account_type = Some(account_type);
+        (secret, account_type)
+    }
+}
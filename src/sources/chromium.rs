@@ -0,0 +1,484 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use super::{leveldb, local_storage::LocalStorageTokens, SourceError, Token, TokenSource};
+use crate::{
+    cache::{fingerprint_dir, Fingerprint},
+    cloud::Cloud,
+    AccountType,
+};
+
+/// The first byte of a Local Storage key or value says how the rest of it is
+/// encoded: UTF-16LE, or Latin-1 when every character fits in one byte.
+const STORAGE_FORMAT_UTF16: u8 = 0;
+const STORAGE_FORMAT_LATIN1: u8 = 1;
+
+/// The parts of Chromium's `Local State` file that describe its profiles.
+#[derive(Deserialize, Debug, Default)]
+struct LocalState {
+    #[serde(default)]
+    profile: LocalStateProfiles,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct LocalStateProfiles {
+    #[serde(default)]
+    info_cache: HashMap<String, ProfileInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileInfo {
+    name: Option<String>,
+}
+
+/// A browser profile directory, e.g. `Default` or `Profile 1`.
+#[derive(Debug)]
+struct BrowserProfile {
+    dir: String,
+    name: Option<String>,
+    path: PathBuf,
+}
+
+impl BrowserProfile {
+    fn leveldb_path(&self) -> PathBuf {
+        self.path.join("Local Storage").join("leveldb")
+    }
+
+    fn matches(&self, profile: &str) -> bool {
+        self.dir == profile || self.name.as_deref() == Some(profile)
+    }
+
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) if name != &self.dir => format!("{} ({})", self.dir, name),
+            _ => self.dir.clone(),
+        }
+    }
+}
+
+/// A Chromium-based browser and where it keeps its user data directories.
+/// Paths are relative to `$HOME` on Linux and macOS, and `%LOCALAPPDATA%` on
+/// Windows.
+pub struct Browser {
+    /// The `--app` value selecting this browser.
+    pub app: &'static str,
+    pub name: &'static str,
+    linux: &'static [&'static str],
+    macos: &'static [&'static str],
+    windows: &'static [&'static str],
+}
+
+pub const BROWSERS: &[Browser] = &[
+    Browser {
+        app: "chrome",
+        name: "Google Chrome",
+        linux: &[
+            ".config/google-chrome",
+            ".var/app/com.google.Chrome/config/google-chrome",
+        ],
+        macos: &["Library/Application Support/Google/Chrome"],
+        windows: &["Google/Chrome/User Data"],
+    },
+    Browser {
+        app: "chromium",
+        name: "Chromium",
+        linux: &[
+            ".config/chromium",
+            "snap/chromium/common/chromium",
+            ".var/app/org.chromium.Chromium/config/chromium",
+        ],
+        macos: &["Library/Application Support/Chromium"],
+        windows: &["Chromium/User Data"],
+    },
+    Browser {
+        app: "edge",
+        name: "Microsoft Edge",
+        linux: &[
+            ".config/microsoft-edge",
+            ".var/app/com.microsoft.Edge/config/microsoft-edge",
+        ],
+        macos: &["Library/Application Support/Microsoft Edge"],
+        windows: &["Microsoft/Edge/User Data"],
+    },
+    Browser {
+        app: "brave",
+        name: "Brave",
+        linux: &[
+            ".config/BraveSoftware/Brave-Browser",
+            "snap/brave/current/.config/BraveSoftware/Brave-Browser",
+            ".var/app/com.brave.Browser/config/BraveSoftware/Brave-Browser",
+        ],
+        macos: &["Library/Application Support/BraveSoftware/Brave-Browser"],
+        windows: &["BraveSoftware/Brave-Browser/User Data"],
+    },
+    Browser {
+        app: "vivaldi",
+        name: "Vivaldi",
+        linux: &[
+            ".config/vivaldi",
+            ".var/app/com.vivaldi.Vivaldi/config/vivaldi",
+        ],
+        macos: &["Library/Application Support/Vivaldi"],
+        windows: &["Vivaldi/User Data"],
+    },
+    // The new (WebView2 based) Teams client. Work and personal accounts live
+    // in the `WV2Profile_tfw` and `WV2Profile_tfl` profiles.
+    Browser {
+        app: "new-teams",
+        name: "Microsoft Teams (new)",
+        linux: &[],
+        macos: &["Library/Containers/com.microsoft.teams2/Data/Library/Application Support/Microsoft/MSTeams/EBWebView"],
+        windows: &["Packages/MSTeams_8wekyb3d8bbwe/LocalCache/Microsoft/MSTeams/EBWebView"],
+    },
+];
+
+impl Browser {
+    /// Every user data directory this browser may use on this platform,
+    /// whether or not it exists.
+    fn user_data_dirs(&self) -> Vec<PathBuf> {
+        let (base, relative_paths) = if cfg!(target_os = "macos") {
+            let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+            (home, self.macos)
+        } else if cfg!(target_os = "windows") {
+            let local_app_data = PathBuf::from(env::var("LOCALAPPDATA").unwrap_or_default());
+            (local_app_data, self.windows)
+        } else {
+            let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+            (home, self.linux)
+        };
+
+        relative_paths
+            .iter()
+            .map(|relative| {
+                relative
+                    .split('/')
+                    .fold(base.clone(), |path, part| path.join(part))
+            })
+            .collect()
+    }
+}
+
+/// Teams web, logged in through a Chromium-based browser.
+pub struct ChromiumSource {
+    pub browser: &'static Browser,
+    /// Profile directory or display name to read. When unset every profile is
+    /// searched.
+    pub profile: Option<String>,
+    /// Cloud whose Teams web origins to read.
+    pub cloud: Cloud,
+}
+
+impl TokenSource for ChromiumSource {
+    fn describe(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("{} (profile {})", self.browser.name, profile),
+            None => self.browser.name.to_string(),
+        }
+    }
+
+    fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+        let dirs = self.browser.user_data_dirs();
+        if dirs.is_empty() {
+            return Err(SourceError::Unsupported(format!(
+                "{} is not available on this platform",
+                self.browser.name
+            )));
+        }
+        if !dirs.iter().any(|dir| dir.is_dir()) {
+            return Err(SourceError::NotFound(dirs[0].clone()));
+        }
+        self.selected_profiles()?;
+        Ok(())
+    }
+
+    fn fingerprint(&self, _account_type: AccountType) -> Option<Fingerprint> {
+        let profiles = self.selected_profiles().ok()?;
+        Some(
+            profiles
+                .iter()
+                .flat_map(|profile| fingerprint_dir(&profile.leveldb_path()))
+                .collect(),
+        )
+    }
+
+    fn paths(&self, _account_type: AccountType) -> Vec<PathBuf> {
+        let mut paths = self.browser.user_data_dirs();
+        if let Ok(profiles) = self.selected_profiles() {
+            paths.extend(profiles.iter().map(BrowserProfile::leveldb_path));
+        }
+        paths
+    }
+
+    fn tokens(&self, account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        let mut tokens = Vec::new();
+        let mut failures = Vec::new();
+        for profile in self.selected_profiles()? {
+            match get_leveldb_tokens(&profile.leveldb_path(), &self.cloud) {
+                Ok(found) => {
+                    let found = found.into_tokens(account_type);
+                    if found.is_empty() {
+                        failures.push(format!("{}: no Teams token", profile.label()));
+                    }
+                    tokens.extend(found.into_iter().map(|mut token| {
+                        token.origin.profile = Some(profile.label());
+                        token.origin.file = Some(profile.leveldb_path());
+                        token
+                    }));
+                }
+                Err(err) => failures.push(format!("{}: {}", profile.label(), err)),
+            }
+        }
+
+        if tokens.is_empty() {
+            return Err(SourceError::NoToken(failures.join("; ")));
+        }
+        Ok(tokens)
+    }
+}
+
+impl ChromiumSource {
+    /// The profiles to search: the one asked for, or every profile that has a
+    /// Local Storage database.
+    fn selected_profiles(&self) -> Result<Vec<BrowserProfile>, SourceError> {
+        let profiles: Vec<BrowserProfile> = self
+            .browser
+            .user_data_dirs()
+            .iter()
+            .flat_map(|dir| browser_profiles(dir))
+            .collect();
+        match &self.profile {
+            Some(wanted) => {
+                let profile = profiles
+                    .into_iter()
+                    .find(|profile| profile.matches(wanted))
+                    .ok_or_else(|| SourceError::NoToken(format!("no profile named {}", wanted)))?;
+                Ok(vec![profile])
+            }
+            None if profiles.is_empty() => Err(SourceError::NoToken(
+                "no profiles with Local Storage found".to_string(),
+            )),
+            None => Ok(profiles),
+        }
+    }
+}
+
+/// Lists the profiles in a Chromium user data directory. Display names come
+/// from `Local State`; any other directory holding a Local Storage database is
+/// included too. `Default` sorts first.
+fn browser_profiles(user_data_dir: &Path) -> Vec<BrowserProfile> {
+    let local_state: LocalState = fs::read(user_data_dir.join("Local State"))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default();
+
+    let mut profiles: Vec<BrowserProfile> = fs::read_dir(user_data_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("Local Storage").join("leveldb").is_dir())
+        .filter_map(|entry| {
+            let dir = entry.file_name().into_string().ok()?;
+            let name = local_state
+                .profile
+                .info_cache
+                .get(&dir)
+                .and_then(|info| info.name.clone());
+            Some(BrowserProfile {
+                dir,
+                name,
+                path: entry.path(),
+            })
+        })
+        .collect();
+
+    profiles.sort_by(|a, b| (a.dir != "Default", &a.dir).cmp(&(b.dir != "Default", &b.dir)));
+    profiles
+}
+
+/// Local Storage key prefixes of the origins Teams web is served from in
+/// `cloud`. Keys are the origin prefixed with `_`, then a NUL and the
+/// script's key.
+fn teams_key_prefixes(cloud: &Cloud) -> Vec<Vec<u8>> {
+    cloud
+        .teams_origins()
+        .into_iter()
+        .map(|origin| format!("_{}\0", origin).into_bytes())
+        .collect()
+}
+
+fn get_leveldb_tokens(
+    leveldb_path: &Path,
+    cloud: &Cloud,
+) -> Result<LocalStorageTokens, SourceError> {
+    let prefixes = teams_key_prefixes(cloud);
+    let prefixes: Vec<&[u8]> = prefixes.iter().map(Vec::as_slice).collect();
+    let entries = leveldb::read_prefixes(leveldb_path, &prefixes)?;
+    Ok(local_storage_tokens(
+        entries,
+        leveldb_path,
+        LocalStorageTokens::new(cloud),
+    ))
+}
+
+/// Adds the tokens among the Local Storage `entries` read from
+/// `leveldb_path` to `tokens`, skipping entries that can't be decoded.
+fn local_storage_tokens(
+    entries: Vec<leveldb::Entry>,
+    leveldb_path: &Path,
+    mut tokens: LocalStorageTokens,
+) -> LocalStorageTokens {
+    for (key, value) in entries {
+        let added =
+            decode_entry(&key, &value).and_then(|(key, value)| tokens.add(&key, value.as_bytes()));
+        if let Err(err) = added {
+            eprintln!(
+                "Skipping malformed Local Storage entry in {}: {}",
+                leveldb_path.display(),
+                err
+            );
+        }
+    }
+    tokens
+}
+
+/// Decodes a Local Storage database entry into the key and value the page
+/// stored. Database keys are `_<origin>\0` followed by the encoded key.
+fn decode_entry(key: &[u8], value: &[u8]) -> Result<(String, String), SourceError> {
+    let script_key = key
+        .iter()
+        .position(|byte| *byte == 0)
+        .map(|separator| &key[separator + 1..])
+        .ok_or_else(|| SourceError::Read("key has no origin separator".to_string()))?;
+    Ok((
+        decode_storage_string(script_key)?,
+        decode_storage_string(value)?,
+    ))
+}
+
+/// Decodes a Local Storage key or value from its prefixed encoding.
+fn decode_storage_string(bytes: &[u8]) -> Result<String, SourceError> {
+    match bytes.split_first() {
+        Some((&STORAGE_FORMAT_LATIN1, latin1)) => {
+            Ok(latin1.iter().map(|byte| char::from(*byte)).collect())
+        }
+        Some((&STORAGE_FORMAT_UTF16, utf16)) => {
+            if utf16.len() % 2 != 0 {
+                return Err(SourceError::Read(
+                    "UTF-16 string has an odd number of bytes".to_string(),
+                ));
+            }
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16(&units)
+                .map_err(|_| SourceError::Read("string is not valid UTF-16".to_string()))
+        }
+        Some((format, _)) => Err(SourceError::Read(format!(
+            "unknown string encoding {:#04x}",
+            format
+        ))),
+        None => Err(SourceError::Read("string is empty".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &[u8] = b"_https://teams.microsoft.com\0";
+
+    fn latin1(text: &str) -> Vec<u8> {
+        let mut bytes = vec![STORAGE_FORMAT_LATIN1];
+        bytes.extend(text.chars().map(|c| u8::try_from(u32::from(c)).unwrap()));
+        bytes
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        let mut bytes = vec![STORAGE_FORMAT_UTF16];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    fn key(script_key: &[u8]) -> Vec<u8> {
+        [ORIGIN, script_key].concat()
+    }
+
+    #[test]
+    fn decodes_latin1_entry() {
+        assert_eq!(
+            decode_entry(&key(&latin1("café")), &latin1("naïve")).unwrap(),
+            ("café".to_string(), "naïve".to_string())
+        );
+    }
+
+    #[test]
+    fn decodes_utf16_entry() {
+        assert_eq!(
+            decode_entry(&key(&utf16("ключ")), &utf16("値 🙂")).unwrap(),
+            ("ключ".to_string(), "値 🙂".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        let mut odd = utf16("token");
+        odd.pop();
+        assert!(decode_storage_string(&odd).is_err());
+        assert!(decode_storage_string(&[0x05, b'a']).is_err());
+        assert!(decode_storage_string(&[]).is_err());
+        assert!(decode_entry(b"_https://teams.microsoft.com", &latin1("value")).is_err());
+    }
+
+    #[test]
+    fn decodes_empty_value() {
+        assert_eq!(decode_storage_string(&latin1("")).unwrap(), "");
+        assert_eq!(decode_storage_string(&utf16("")).unwrap(), "");
+    }
+
+    #[test]
+    fn skips_malformed_entries() {
+        let skype_token = |token: &str| format!(r#"{{"skypeToken":"{}","expiration":1}}"#, token);
+        let entries = vec![
+            (
+                key(&latin1("auth.skype.token")),
+                latin1(&skype_token("latin1")),
+            ),
+            (key(&[0x05, b'x']), latin1(&skype_token("unknown encoding"))),
+            (
+                key(&utf16("other.auth.skype.token")),
+                utf16(&skype_token("utf16")),
+            ),
+            (key(&latin1("broken.auth.skype.token")), latin1("{")),
+        ];
+
+        let tokens = local_storage_tokens(
+            entries,
+            Path::new("leveldb"),
+            LocalStorageTokens::new(&Cloud::Commercial),
+        )
+        .into_tokens(AccountType::Live)
+        .into_iter()
+        .map(|token| token.token)
+        .collect::<Vec<_>>();
+        assert_eq!(tokens, vec!["latin1", "utf16"]);
+    }
+
+    #[test]
+    fn reads_the_clouds_origins() {
+        let prefixes = teams_key_prefixes(&Cloud::Custom("http://127.0.0.1:8080".to_string()));
+        assert_eq!(prefixes[0], b"_http://127.0.0.1:8080\0");
+        assert!(prefixes.contains(&ORIGIN.to_vec()));
+        assert_eq!(
+            teams_key_prefixes(&Cloud::Gcch),
+            [
+                b"_https://gov.teams.microsoft.us\0".to_vec(),
+                b"_https://teams.microsoft.us\0".to_vec()
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Decryption of Chromium/Electron `encrypted_value` cookies on Linux.
+//!
+//! Cookies are prefixed with `v10` or `v11` and encrypted with AES-128-CBC
+//! using a key derived from a password with PBKDF2-HMAC-SHA1. `v10` cookies
+//! use the hard-coded password "peanuts"; `v11` cookies use a password kept
+//! in the desktop keyring (Secret Service), which must be supplied by the user.
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use hmac::Hmac;
+use sha1::Sha1;
+
+use super::SourceError;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+const SALT: &[u8] = b"saltysalt";
+const IV: [u8; 16] = [b' '; 16];
+pub const V10_PASSWORD: &str = "peanuts";
+
+/// Cookie databases at or above this `meta` version prepend a SHA-256 hash of
+/// the cookie's host to the plaintext.
+const HOST_HASH_DB_VERSION: u32 = 24;
+
+fn derive_key(password: &str) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    pbkdf2::pbkdf2::<Hmac<Sha1>>(password.as_bytes(), SALT, 1, &mut key);
+    key
+}
+
+/// Decrypts an `encrypted_value` blob. `password` is only used for `v11`
+/// cookies, and `db_version` is the `version` from the database's `meta` table.
+pub fn decrypt_cookie(
+    encrypted: &[u8],
+    password: Option<&str>,
+    db_version: u32,
+) -> Result<String, SourceError> {
+    if !cfg!(target_os = "linux") {
+        return Err(SourceError::Unsupported(
+            "decrypting cookies is only supported on linux".to_string(),
+        ));
+    }
+
+    let (password, ciphertext) = if let Some(ciphertext) = encrypted.strip_prefix(b"v10") {
+        (V10_PASSWORD, ciphertext)
+    } else if let Some(ciphertext) = encrypted.strip_prefix(b"v11") {
+        let password = password.ok_or_else(|| {
+            SourceError::Read(
+                "cookie is encrypted with the keyring password, set `cookie_password` in the \
+                 config or TPCLI_COOKIE_PASSWORD"
+                    .to_string(),
+            )
+        })?;
+        (password, ciphertext)
+    } else {
+        return Err(SourceError::Read(
+            "unknown cookie encryption version".to_string(),
+        ));
+    };
+
+    let key = derive_key(password);
+    let plaintext = Aes128CbcDec::new(&key.into(), &IV.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| SourceError::Read("failed to decrypt cookie, wrong password?".to_string()))?;
+
+    let plaintext = if db_version >= HOST_HASH_DB_VERSION && plaintext.len() >= 32 {
+        &plaintext[32..]
+    } else {
+        &plaintext[..]
+    };
+
+    String::from_utf8(plaintext.to_vec())
+        .map_err(|_| SourceError::Read("decrypted cookie is not valid UTF-8".to_string()))
+}
+
+/// Encrypts `plaintext` the way Chromium does, for fixture cookies. `v10`
+/// cookies must use [`V10_PASSWORD`].
+#[cfg(all(test, target_os = "linux"))]
+pub fn encrypt(version: &[u8], password: &str, plaintext: &[u8]) -> Vec<u8> {
+    use aes::cipher::BlockEncryptMut;
+
+    let mut encrypted = version.to_vec();
+    encrypted.extend(
+        cbc::Encryptor::<aes::Aes128>::new(&derive_key(password).into(), &IV.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext),
+    );
+    encrypted
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypts_v10_with_the_builtin_password() {
+        let encrypted = encrypt(b"v10", V10_PASSWORD, b"skype-token");
+        assert_eq!(decrypt_cookie(&encrypted, None, 0).unwrap(), "skype-token");
+    }
+
+    #[test]
+    fn decrypts_v11_with_the_keyring_password() {
+        let encrypted = encrypt(b"v11", "keyring secret", b"skype-token");
+        assert_eq!(
+            decrypt_cookie(&encrypted, Some("keyring secret"), 0).unwrap(),
+            "skype-token"
+        );
+    }
+
+    #[test]
+    fn v11_needs_a_password() {
+        let encrypted = encrypt(b"v11", "keyring secret", b"skype-token");
+        assert!(matches!(
+            decrypt_cookie(&encrypted, None, 0),
+            Err(SourceError::Read(_))
+        ));
+    }
+
+    #[test]
+    fn v11_with_the_wrong_password_fails() {
+        let encrypted = encrypt(b"v11", "keyring secret", b"skype-token");
+        assert!(decrypt_cookie(&encrypted, Some("wrong"), 0).is_err());
+    }
+
+    #[test]
+    fn strips_the_host_hash_from_newer_databases() {
+        let mut plaintext = vec![0xab; 32];
+        plaintext.extend_from_slice(b"skype-token");
+        let encrypted = encrypt(b"v10", V10_PASSWORD, &plaintext);
+        assert_eq!(
+            decrypt_cookie(&encrypted, None, HOST_HASH_DB_VERSION).unwrap(),
+            "skype-token"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(decrypt_cookie(b"v99ciphertext", None, 0).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{fs, path::PathBuf, sync::Mutex, time::SystemTime};
+
+use super::{SourceError, Token, TokenOrigin, TokenSource};
+use crate::{jwt::Jwt, now, output::epoch_time, AccountType};
+
+/// Where an explicitly supplied token comes from.
+#[derive(Debug)]
+pub enum TokenInput {
+    /// Given on the command line, in `TPCLI_TOKEN` or on stdin. The string
+    /// names where it came from, for error messages.
+    Value(String, &'static str),
+    /// Read from a file, and read again whenever the file changes.
+    File(PathBuf),
+}
+
+/// A token handed to tpcli directly rather than scraped from an app. The
+/// account type comes from `--account`.
+pub struct ExplicitSource {
+    input: TokenInput,
+    /// The file's modification time and contents as of the last read.
+    file_cache: Mutex<Option<(SystemTime, String)>>,
+}
+
+impl ExplicitSource {
+    pub fn new(input: TokenInput) -> Self {
+        ExplicitSource {
+            input,
+            file_cache: Mutex::new(None),
+        }
+    }
+
+    fn read_file(&self, path: &PathBuf) -> Result<String, SourceError> {
+        let modified = fs::metadata(path)?.modified()?;
+        let mut cache = self.file_cache.lock().unwrap();
+        if let Some((cached_modified, token)) = cache.as_ref() {
+            if *cached_modified == modified {
+                return Ok(token.clone());
+            }
+        }
+
+        let token = fs::read_to_string(path)?.trim().to_string();
+        *cache = Some((modified, token.clone()));
+        Ok(token)
+    }
+}
+
+impl TokenSource for ExplicitSource {
+    fn describe(&self) -> String {
+        match &self.input {
+            TokenInput::Value(_, origin) => origin.to_string(),
+            TokenInput::File(path) => format!("token file ({})", path.display()),
+        }
+    }
+
+    fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+        match &self.input {
+            TokenInput::Value(..) => Ok(()),
+            TokenInput::File(path) if path.is_file() => Ok(()),
+            TokenInput::File(path) => Err(SourceError::NotFound(path.clone())),
+        }
+    }
+
+    fn account_agnostic(&self) -> bool {
+        true
+    }
+
+    fn paths(&self, _account_type: AccountType) -> Vec<PathBuf> {
+        match &self.input {
+            TokenInput::Value(..) => Vec::new(),
+            TokenInput::File(path) => vec![path.clone()],
+        }
+    }
+
+    fn changed(&self) -> bool {
+        let path = match &self.input {
+            TokenInput::File(path) => path,
+            TokenInput::Value(..) => return false,
+        };
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+        match (self.file_cache.lock().unwrap().as_ref(), modified) {
+            (Some((cached_modified, _)), Ok(modified)) => *cached_modified != modified,
+            _ => false,
+        }
+    }
+
+    fn tokens(&self, _account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        let token = match &self.input {
+            TokenInput::Value(token, _) => token.trim().to_string(),
+            TokenInput::File(path) => self.read_file(path)?,
+        };
+        if token.is_empty() {
+            return Err(SourceError::Invalid("the token is empty".to_string()));
+        }
+
+        let jwt = Jwt { token };
+        let expiration = jwt
+            .claims()
+            .map_err(|e| SourceError::Invalid(format!("the token is {}", e)))?
+            .exp
+            .ok_or_else(|| SourceError::Invalid("the token has no expiry".to_string()))?;
+        if expiration <= now() {
+            return Err(SourceError::Invalid(format!(
+                "the token expired at {}",
+                epoch_time(expiration)
+            )));
+        }
+
+        Ok(vec![Token {
+            token: jwt.token,
+            expiration,
+            refresh: None,
+            origin: match &self.input {
+                TokenInput::Value(..) => TokenOrigin::default(),
+                TokenInput::File(path) => TokenOrigin::file(path.clone()),
+            },
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::RetryPolicy, jwt, refresh::TokenManager, sources::AccountFilter};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn write_token(path: &PathBuf, user: &str, modified: SystemTime) {
+        let token = jwt::fake(serde_json::json!({ "upn": user, "exp": 4102444800u64 }));
+        fs::write(path, format!("{}\n", token)).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn user(source: &ExplicitSource) -> Option<String> {
+        let token = source.tokens(AccountType::Microsoft).unwrap().remove(0);
+        Jwt { token: token.token }.claims().unwrap().upn
+    }
+
+    #[test]
+    fn rereads_the_file_once_it_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        write_token(&path, "alice@contoso.com", UNIX_EPOCH);
+        let source = ExplicitSource::new(TokenInput::File(path.clone()));
+
+        assert_eq!(user(&source).as_deref(), Some("alice@contoso.com"));
+        assert!(!source.changed());
+
+        write_token(
+            &path,
+            "bob@contoso.com",
+            UNIX_EPOCH + Duration::from_secs(60),
+        );
+        assert!(source.changed());
+        assert_eq!(user(&source).as_deref(), Some("bob@contoso.com"));
+        assert!(!source.changed());
+    }
+
+    #[tokio::test]
+    async fn token_manager_picks_up_a_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        write_token(&path, "alice@contoso.com", UNIX_EPOCH);
+        let sources: Vec<Box<dyn TokenSource>> = vec![Box::new(ExplicitSource::new(
+            TokenInput::File(path.clone()),
+        ))];
+        let mut tokens = TokenManager::new(
+            &sources,
+            Some(AccountType::Microsoft),
+            AccountFilter::default(),
+            RetryPolicy::default(),
+        );
+        let user = |token: String| Jwt { token }.claims().unwrap().upn;
+
+        let (token, _) = tokens.token().await.unwrap();
+        assert_eq!(user(token).as_deref(), Some("alice@contoso.com"));
+        write_token(
+            &path,
+            "bob@contoso.com",
+            UNIX_EPOCH + Duration::from_secs(60),
+        );
+        let (token, _) = tokens.token().await.unwrap();
+        assert_eq!(user(token).as_deref(), Some("bob@contoso.com"));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let token = jwt::fake(serde_json::json!({ "exp": 1 }));
+        let source = ExplicitSource::new(TokenInput::Value(token, "--token"));
+        assert!(matches!(
+            source.tokens(AccountType::Microsoft),
+            Err(SourceError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,466 @@
+use rusqlite::Connection;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+use tempfile::tempdir;
+
+use super::{local_storage::LocalStorageTokens, SourceError, Token, TokenSource};
+use crate::{
+    cache::{fingerprint_files, Fingerprint},
+    cloud::Cloud,
+    AccountType,
+};
+
+/// `compression_type` of snappy-compressed values in `data.sqlite`.
+const COMPRESSION_SNAPPY: i64 = 1;
+/// `conversion_type` of values that were converted from UTF-16 to UTF-8.
+const CONVERSION_UTF16_UTF8: i64 = 1;
+
+/// A profile listed in `profiles.ini`.
+#[derive(Debug)]
+struct FirefoxProfile {
+    name: Option<String>,
+    path: PathBuf,
+}
+
+impl FirefoxProfile {
+    fn matches(&self, profile: &str) -> bool {
+        self.name.as_deref() == Some(profile)
+            || self.path.file_name().and_then(|name| name.to_str()) == Some(profile)
+    }
+
+    /// The Local Storage databases Teams web in `cloud` may have written to.
+    fn databases(&self, cloud: &Cloud) -> Vec<PathBuf> {
+        cloud
+            .teams_origins()
+            .iter()
+            .map(|origin| {
+                self.path
+                    .join("storage")
+                    .join("default")
+                    .join(origin_dir(origin))
+                    .join("ls")
+                    .join("data.sqlite")
+            })
+            .collect()
+    }
+
+    fn label(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.path.display().to_string())
+    }
+}
+
+/// The per-origin storage directory name Firefox uses for `origin`, e.g.
+/// `https+++teams.microsoft.com`.
+fn origin_dir(origin: &str) -> String {
+    origin.replacen("://", "+++", 1).replace(':', "+")
+}
+
+/// Teams web, logged in through Firefox.
+pub struct FirefoxSource {
+    /// Profile name or directory to read. When unset every profile is searched.
+    pub profile: Option<String>,
+    /// Cloud whose Teams web origins to read.
+    pub cloud: Cloud,
+}
+
+impl TokenSource for FirefoxSource {
+    fn describe(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("Firefox (profile {})", profile),
+            None => "Firefox".to_string(),
+        }
+    }
+
+    fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+        if !firefox_dirs()
+            .iter()
+            .any(|dir| dir.join("profiles.ini").is_file())
+        {
+            let dir = firefox_dirs().remove(0);
+            return Err(SourceError::NotFound(dir.join("profiles.ini")));
+        }
+        self.selected_profiles()?;
+        Ok(())
+    }
+
+    fn fingerprint(&self, _account_type: AccountType) -> Option<Fingerprint> {
+        let paths = self
+            .selected_profiles()
+            .ok()?
+            .into_iter()
+            .flat_map(|profile| profile.databases(&self.cloud))
+            .flat_map(|database| [database.with_file_name("data.sqlite-wal"), database]);
+        Some(fingerprint_files(paths))
+    }
+
+    fn paths(&self, _account_type: AccountType) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = firefox_dirs()
+            .into_iter()
+            .map(|dir| dir.join("profiles.ini"))
+            .collect();
+        if let Ok(profiles) = self.selected_profiles() {
+            paths.extend(
+                profiles
+                    .iter()
+                    .flat_map(|profile| profile.databases(&self.cloud)),
+            );
+        }
+        paths
+    }
+
+    fn tokens(&self, account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        let mut tokens = Vec::new();
+        let mut failures = Vec::new();
+        for profile in self.selected_profiles()? {
+            let databases: Vec<PathBuf> = profile
+                .databases(&self.cloud)
+                .into_iter()
+                .filter(|path| path.is_file())
+                .collect();
+            if databases.is_empty() {
+                failures.push(format!(
+                    "{}: Teams web has not stored any data",
+                    profile.label()
+                ));
+                continue;
+            }
+
+            for database in databases {
+                match get_sqlite_local_storage_tokens(&database, &self.cloud) {
+                    Ok(found) => {
+                        let found = found.into_tokens(account_type);
+                        if found.is_empty() {
+                            failures.push(format!("{}: no Teams token", profile.label()));
+                        }
+                        tokens.extend(found.into_iter().map(|mut token| {
+                            token.origin.profile = Some(profile.label());
+                            token.origin.file = Some(database.clone());
+                            token
+                        }));
+                    }
+                    Err(err) => failures.push(format!("{}: {}", profile.label(), err)),
+                }
+            }
+        }
+
+        if tokens.is_empty() {
+            return Err(SourceError::NoToken(failures.join("; ")));
+        }
+        Ok(tokens)
+    }
+}
+
+impl FirefoxSource {
+    fn selected_profiles(&self) -> Result<Vec<FirefoxProfile>, SourceError> {
+        let profiles: Vec<FirefoxProfile> = firefox_dirs()
+            .iter()
+            .flat_map(|dir| firefox_profiles(dir))
+            .collect();
+        match &self.profile {
+            Some(wanted) => {
+                let profile = profiles
+                    .into_iter()
+                    .find(|profile| profile.matches(wanted))
+                    .ok_or_else(|| SourceError::NoToken(format!("no profile named {}", wanted)))?;
+                Ok(vec![profile])
+            }
+            None if profiles.is_empty() => Err(SourceError::NoToken(
+                "no profiles found in profiles.ini".to_string(),
+            )),
+            None => Ok(profiles),
+        }
+    }
+}
+
+/// Every directory Firefox may keep `profiles.ini` in on this platform,
+/// including Snap and Flatpak installs on Linux.
+fn firefox_dirs() -> Vec<PathBuf> {
+    if cfg!(target_os = "macos") {
+        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+        vec![home
+            .join("Library")
+            .join("Application Support")
+            .join("Firefox")]
+    } else if cfg!(target_os = "windows") {
+        let app_data = PathBuf::from(env::var("APPDATA").unwrap_or_default());
+        vec![app_data.join("Mozilla").join("Firefox")]
+    } else {
+        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+        vec![
+            home.join(".mozilla").join("firefox"),
+            home.join("snap")
+                .join("firefox")
+                .join("common")
+                .join(".mozilla")
+                .join("firefox"),
+            home.join(".var")
+                .join("app")
+                .join("org.mozilla.firefox")
+                .join(".mozilla")
+                .join("firefox"),
+        ]
+    }
+}
+
+/// Reads the `[ProfileN]` sections of `profiles.ini` in `firefox_dir`.
+fn firefox_profiles(firefox_dir: &Path) -> Vec<FirefoxProfile> {
+    let contents = match fs::read_to_string(firefox_dir.join("profiles.ini")) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut sections: Vec<(&str, HashMap<&str, &str>)> = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((section, HashMap::new()));
+        } else if let (Some((_, entries)), Some((key, value))) =
+            (sections.last_mut(), line.split_once('='))
+        {
+            entries.insert(key, value);
+        }
+    }
+
+    sections
+        .into_iter()
+        .filter(|(section, _)| section.starts_with("Profile"))
+        .filter_map(|(_, entries)| {
+            let path = entries.get("Path")?;
+            let path = if entries.get("IsRelative") == Some(&"0") {
+                PathBuf::from(path)
+            } else {
+                firefox_dir.join(path)
+            };
+            Some(FirefoxProfile {
+                name: entries.get("Name").map(|name| name.to_string()),
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Decodes a `data.sqlite` value into the string Teams stored.
+fn decode_value(
+    raw: &[u8],
+    compression_type: i64,
+    conversion_type: i64,
+) -> Result<String, SourceError> {
+    let bytes = if compression_type == COMPRESSION_SNAPPY {
+        snap::raw::Decoder::new()
+            .decompress_vec(raw)
+            .map_err(|e| SourceError::Read(format!("failed to decompress value: {}", e)))?
+    } else {
+        raw.to_vec()
+    };
+
+    // Unconverted values are kept as UTF-16 unless they were pure ASCII.
+    if conversion_type != CONVERSION_UTF16_UTF8
+        && bytes.len() % 2 == 0
+        && bytes.iter().skip(1).step_by(2).all(|b| *b == 0)
+        && !bytes.is_empty()
+    {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        return String::from_utf16(&units)
+            .map_err(|_| SourceError::Read("value is not valid UTF-16".to_string()));
+    }
+
+    String::from_utf8(bytes).map_err(|_| SourceError::Read("value is not valid UTF-8".to_string()))
+}
+
+/// Scans a Firefox per-origin Local Storage database. The database (and its
+/// write-ahead log) is copied first so a running Firefox does not block us.
+fn get_sqlite_local_storage_tokens(
+    sqlite_path: &Path,
+    cloud: &Cloud,
+) -> Result<LocalStorageTokens, SourceError> {
+    let temp_db_dir = tempdir()?;
+    let temp_path = temp_db_dir.path().join("data.sqlite");
+    fs::copy(sqlite_path, &temp_path)?;
+    let wal_path = sqlite_path.with_file_name("data.sqlite-wal");
+    if wal_path.is_file() {
+        fs::copy(&wal_path, temp_db_dir.path().join("data.sqlite-wal"))?;
+    }
+
+    let conn = Connection::open(&temp_path)?;
+    let mut stmt =
+        conn.prepare("select key, value, compression_type, conversion_type from data")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get_ref(1)?.as_bytes()?.to_vec(),
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut tokens = LocalStorageTokens::new(cloud);
+    for (key, value, compression_type, conversion_type) in rows {
+        let added = decode_value(&value, compression_type, conversion_type)
+            .and_then(|value| tokens.add(&key, value.as_bytes()));
+        if let Err(err) = added {
+            eprintln!(
+                "Skipping malformed Local Storage entry {} in {}: {}",
+                key,
+                sqlite_path.display(),
+                err
+            );
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Firefox's `data.sqlite` schema.
+    const SCHEMA: &str = "create table data (
+        key text primary key,
+        utf16_length integer not null,
+        conversion_type integer not null,
+        compression_type integer not null,
+        last_access_time integer not null default 0,
+        value blob not null
+    )";
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn names_origin_directories() {
+        assert_eq!(
+            origin_dir("https://teams.microsoft.com"),
+            "https+++teams.microsoft.com"
+        );
+        assert_eq!(origin_dir("http://127.0.0.1:8080"), "http+++127.0.0.1+8080");
+    }
+
+    #[test]
+    fn reads_profiles_ini() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("profiles.ini"),
+            "[Install4F96D1932A9F858E]\nDefault=Profiles/abc.default-release\n\n\
+             [Profile0]\nName=default-release\nIsRelative=1\nPath=Profiles/abc.default-release\n\n\
+             [Profile1]\nName=work\nIsRelative=0\nPath=/srv/firefox/work\n\n\
+             [Profile2]\nName=no path\n\n\
+             [General]\nVersion=2\n",
+        )
+        .unwrap();
+
+        let profiles = firefox_profiles(dir.path());
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].name.as_deref(), Some("default-release"));
+        assert_eq!(
+            profiles[0].path,
+            dir.path().join("Profiles/abc.default-release")
+        );
+        assert!(profiles[0].matches("abc.default-release"));
+        assert_eq!(profiles[1].path, PathBuf::from("/srv/firefox/work"));
+        assert!(profiles[1].matches("work"));
+        assert!(firefox_profiles(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn decodes_values() {
+        let compressed = snap::raw::Encoder::new()
+            .compress_vec(b"{\"a\":1}")
+            .unwrap();
+        assert_eq!(
+            decode_value(&compressed, COMPRESSION_SNAPPY, CONVERSION_UTF16_UTF8).unwrap(),
+            "{\"a\":1}"
+        );
+        assert_eq!(decode_value(&utf16("hé"), 0, 0).unwrap(), "hé");
+        assert_eq!(
+            decode_value("héllo".as_bytes(), 0, CONVERSION_UTF16_UTF8).unwrap(),
+            "héllo"
+        );
+        assert!(decode_value(b"\xff\xff\xff", COMPRESSION_SNAPPY, 0).is_err());
+        assert!(decode_value(b"\xff", 0, CONVERSION_UTF16_UTF8).is_err());
+    }
+
+    #[test]
+    fn reads_data_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("profiles.ini"),
+            "[Profile0]\nName=default\nIsRelative=1\nPath=abc.default\n",
+        )
+        .unwrap();
+        let profile = firefox_profiles(dir.path()).remove(0);
+        let database = profile.databases(&Cloud::Commercial).remove(0);
+        fs::create_dir_all(database.parent().unwrap()).unwrap();
+
+        let conn = Connection::open(&database).unwrap();
+        conn.execute(SCHEMA, []).unwrap();
+        let insert = |key: &str, value: &[u8], compression_type: i64, conversion_type: i64| {
+            conn.execute(
+                "insert into data (key, utf16_length, conversion_type, compression_type, value) \
+                 values (?1, 0, ?2, ?3, ?4)",
+                params![key, conversion_type, compression_type, value],
+            )
+            .unwrap();
+        };
+        let skype_token = json!({ "skypeToken": "skype", "expiration": 4102444800u64 });
+        insert(
+            "auth.skype.token",
+            &snap::raw::Encoder::new()
+                .compress_vec(skype_token.to_string().as_bytes())
+                .unwrap(),
+            COMPRESSION_SNAPPY,
+            CONVERSION_UTF16_UTF8,
+        );
+        let presence_token = json!({ "token": "presence", "expiration": 4102444800u64 });
+        insert(
+            "ts.oid.cache.token.https://presence.teams.microsoft.com/",
+            &utf16(&presence_token.to_string()),
+            0,
+            0,
+        );
+        // Neither stops the rest from being read.
+        insert(
+            "ts.oid.cache.token.https://corrupt",
+            b"\xff\xff\xff",
+            COMPRESSION_SNAPPY,
+            0,
+        );
+        insert(
+            "old.auth.skype.token",
+            b"not json",
+            0,
+            CONVERSION_UTF16_UTF8,
+        );
+        drop(conn);
+
+        let tokens = |account_type| {
+            get_sqlite_local_storage_tokens(&database, &Cloud::Commercial)
+                .unwrap()
+                .into_tokens(account_type)
+                .into_iter()
+                .map(|token| (token.token, token.expiration))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tokens(AccountType::Microsoft),
+            [("presence".to_string(), 4102444800)]
+        );
+        assert_eq!(
+            tokens(AccountType::Live),
+            [("skype".to_string(), 4102444800)]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! External token providers, in the spirit of git credential helpers.
+//!
+//! The helper command is run through the shell with a JSON request on stdin:
+//!
+//! ```json
+//! {"account_type": "ms", "tenant": null, "audience": "https://presence.teams.microsoft.com/"}
+//! ```
+//!
+//! and prints either a JSON response, `{"token": "...", "expires_at": 1700000000}`,
+//! or just the raw token (as `pass show` would). Without `expires_at` the expiry
+//! is read from the token itself.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use super::{SourceError, Token, TokenOrigin, TokenSource};
+use crate::{cloud::Cloud, jwt::Jwt, AccountType};
+
+#[derive(Serialize, Debug)]
+struct HelperRequest<'a> {
+    account_type: &'a str,
+    tenant: Option<&'a str>,
+    audience: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct HelperResponse {
+    token: String,
+    expires_at: Option<u64>,
+}
+
+/// Runs the `token_helper` command from the config.
+pub struct HelperSource {
+    pub command: String,
+    pub tenant: Option<String>,
+    /// Cloud the token is for, which sets the audience asked for.
+    pub cloud: Cloud,
+}
+
+impl HelperSource {
+    fn run(&self, request: &HelperRequest) -> Result<String, SourceError> {
+        let mut command = if cfg!(target_os = "windows") {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&self.command);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&self.command);
+            command
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| SourceError::Read(format!("failed to run token helper: {}", e)))?;
+
+        // Helpers that don't care about the request may exit without reading
+        // it, so a broken pipe here is fine.
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(&serde_json::to_vec(request).unwrap());
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(SourceError::Read(format!(
+                "token helper exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| SourceError::Read("token helper output is not UTF-8".to_string()))
+    }
+}
+
+impl TokenSource for HelperSource {
+    fn describe(&self) -> String {
+        format!("token helper ({})", self.command)
+    }
+
+    fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+        Ok(())
+    }
+
+    /// Most helpers hand back the same token whatever they are asked for, and
+    /// running one twice may prompt twice.
+    fn account_agnostic(&self) -> bool {
+        true
+    }
+
+    fn tokens(&self, account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        let audience = self.cloud.audience(account_type);
+        let request = HelperRequest {
+            account_type: account_type.name(),
+            tenant: self.tenant.as_deref(),
+            audience: &audience,
+        };
+        let output = self.run(&request)?;
+        let output = output.trim();
+
+        let response = match serde_json::from_str::<HelperResponse>(output) {
+            Ok(response) => response,
+            Err(_) => HelperResponse {
+                token: output.lines().next().unwrap_or_default().trim().to_string(),
+                expires_at: None,
+            },
+        };
+        if response.token.is_empty() {
+            return Err(SourceError::NoToken(
+                "token helper printed nothing".to_string(),
+            ));
+        }
+
+        let jwt = Jwt {
+            token: response.token,
+        };
+        let expiration = response.expires_at.or_else(|| jwt.exp()).ok_or_else(|| {
+            SourceError::Invalid(
+                "token helper gave no expires_at and the token has no expiry".to_string(),
+            )
+        })?;
+
+        Ok(vec![Token {
+            token: jwt.token,
+            expiration,
+            refresh: None,
+            origin: TokenOrigin::default(),
+        }])
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use serde_json::{json, Value};
+    use std::fs;
+
+    use super::*;
+    use crate::jwt;
+
+    fn helper(command: &str) -> HelperSource {
+        HelperSource {
+            command: command.to_string(),
+            tenant: None,
+            cloud: Cloud::Commercial,
+        }
+    }
+
+    #[test]
+    fn sends_the_request_and_reads_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = dir.path().join("request.json");
+        let source = HelperSource {
+            tenant: Some("contoso.com".to_string()),
+            ..helper(&format!(
+                r#"cat >'{}'; echo '{{"token": "abc", "expires_at": 4102444800}}'"#,
+                request.display()
+            ))
+        };
+
+        let tokens = source.tokens(AccountType::Microsoft).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].token, "abc");
+        assert_eq!(tokens[0].expiration, 4102444800);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&fs::read(&request).unwrap()).unwrap(),
+            json!({
+                "account_type": "ms",
+                "tenant": "contoso.com",
+                "audience": Cloud::Commercial.audience(AccountType::Microsoft),
+            })
+        );
+
+        source.tokens(AccountType::Live).unwrap();
+        let request = serde_json::from_slice::<Value>(&fs::read(&request).unwrap()).unwrap();
+        assert_eq!(request["account_type"], "live");
+        assert_eq!(
+            request["audience"],
+            Cloud::Commercial.audience(AccountType::Live)
+        );
+    }
+
+    #[test]
+    fn reads_bare_tokens() {
+        let token = jwt::fake(json!({ "exp": 4102444800u64 }));
+        let tokens = helper(&format!("printf '%s\\n' '{}'", token))
+            .tokens(AccountType::Microsoft)
+            .unwrap();
+        assert_eq!(tokens[0].token, token);
+        assert_eq!(tokens[0].expiration, 4102444800);
+
+        // Neither `expires_at` nor an expiry in the token.
+        assert!(matches!(
+            helper("printf token").tokens(AccountType::Microsoft),
+            Err(SourceError::Invalid(_))
+        ));
+        assert!(matches!(
+            helper("true").tokens(AccountType::Microsoft),
+            Err(SourceError::NoToken(_))
+        ));
+    }
+
+    #[test]
+    fn reports_failures() {
+        match helper("echo oops >&2; exit 3").tokens(AccountType::Microsoft) {
+            Err(SourceError::Read(message)) => {
+                assert!(message.contains("exit status: 3"), "{}", message);
+                assert!(message.ends_with(": oops"), "{}", message);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
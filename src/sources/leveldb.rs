@@ -0,0 +1,543 @@
+//! A read-only reader for LevelDB databases, just enough to read Chromium's
+//! Local Storage in place.
+//!
+//! Browsers hold LevelDB's `LOCK` file while running, which the LevelDB
+//! library refuses to open around. Reading the files directly avoids both the
+//! lock and copying the database: table files (`.ldb`, `.sst`) are immutable
+//! once written, and the write-ahead log (`.log`) is only ever appended to, so
+//! a torn final record is simply ignored.
+//!
+//! A compaction can replace or delete files while they are being read, so
+//! only the tables and logs the current `MANIFEST` lists as live are read,
+//! and a table that has gone or is unreadable is skipped rather than failing
+//! the whole read. Without a readable `MANIFEST`, every file is read.
+//!
+//! Only keys under the requested prefixes are read. Table files are sorted, so
+//! their index blocks are used to skip straight to the matching key range and
+//! stop once past it. Checksums are not verified.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::SourceError;
+
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+const FOOTER_LEN: usize = 48;
+/// Compression type and CRC following every table block.
+const BLOCK_TRAILER_LEN: usize = 5;
+const COMPRESSION_SNAPPY: u8 = 1;
+
+const LOG_BLOCK_LEN: usize = 32768;
+const LOG_HEADER_LEN: usize = 7;
+const LOG_FULL: u8 = 1;
+const LOG_FIRST: u8 = 2;
+const LOG_MIDDLE: u8 = 3;
+const LOG_LAST: u8 = 4;
+
+const TYPE_DELETION: u8 = 0;
+const TYPE_VALUE: u8 = 1;
+
+// Version edit tags in the MANIFEST.
+const TAG_COMPARATOR: u64 = 1;
+const TAG_LOG_NUMBER: u64 = 2;
+const TAG_NEXT_FILE_NUMBER: u64 = 3;
+const TAG_LAST_SEQUENCE: u64 = 4;
+const TAG_COMPACT_POINTER: u64 = 5;
+const TAG_DELETED_FILE: u64 = 6;
+const TAG_NEW_FILE: u64 = 7;
+const TAG_PREV_LOG_NUMBER: u64 = 9;
+
+fn corrupt(path: &Path, what: &str) -> SourceError {
+    SourceError::Read(format!("{}: {}", path.display(), what))
+}
+
+/// Cursor over a byte slice for LevelDB's varint encoded structures.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut result = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            result |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn fixed32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn fixed64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn length_prefixed(&mut self) -> Option<&'a [u8]> {
+        let len = self.varint()? as usize;
+        self.bytes(len)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/// A key and its value, as read from the database.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// The newest version of each key seen so far. `None` marks a deletion.
+#[derive(Default)]
+struct Entries {
+    newest: HashMap<Vec<u8>, (u64, Option<Vec<u8>>)>,
+}
+
+impl Entries {
+    fn insert(&mut self, key: &[u8], sequence: u64, value: Option<&[u8]>) {
+        match self.newest.get(key) {
+            Some((newest_sequence, _)) if *newest_sequence >= sequence => {}
+            _ => {
+                self.newest
+                    .insert(key.to_vec(), (sequence, value.map(<[u8]>::to_vec)));
+            }
+        }
+    }
+}
+
+/// Splits an internal key into the user key, sequence number and value type.
+fn parse_internal_key(internal_key: &[u8]) -> Option<(&[u8], u64, u8)> {
+    let split = internal_key.len().checked_sub(8)?;
+    let (user_key, trailer) = internal_key.split_at(split);
+    let trailer = u64::from_le_bytes(trailer.try_into().ok()?);
+    Some((user_key, trailer >> 8, (trailer & 0xff) as u8))
+}
+
+/// Decodes the `(key, value)` entries of a table block.
+fn block_entries(block: &[u8]) -> Option<Vec<(Vec<u8>, &[u8])>> {
+    let num_restarts =
+        u32::from_le_bytes(block.get(block.len().checked_sub(4)?..)?.try_into().ok()?);
+    let restarts_len = (num_restarts as usize + 1).checked_mul(4)?;
+    let data = block.get(..block.len().checked_sub(restarts_len)?)?;
+
+    let mut reader = Reader::new(data);
+    let mut entries = Vec::new();
+    let mut key: Vec<u8> = Vec::new();
+    while !reader.is_empty() {
+        let shared = reader.varint()? as usize;
+        let non_shared = reader.varint()? as usize;
+        let value_len = reader.varint()? as usize;
+        key.truncate(shared);
+        key.extend_from_slice(reader.bytes(non_shared)?);
+        entries.push((key.clone(), reader.bytes(value_len)?));
+    }
+    Some(entries)
+}
+
+struct Table {
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+impl Table {
+    /// Reads the block at `handle`, decompressing it if needed.
+    fn block(&self, handle: &[u8]) -> Result<Vec<u8>, SourceError> {
+        let mut reader = Reader::new(handle);
+        let (offset, size) = match (reader.varint(), reader.varint()) {
+            (Some(offset), Some(size)) => (offset as usize, size as usize),
+            _ => return Err(corrupt(&self.path, "bad block handle")),
+        };
+        let raw = offset
+            .checked_add(size)
+            .and_then(|end| end.checked_add(BLOCK_TRAILER_LEN))
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| corrupt(&self.path, "block out of range"))?;
+        let (contents, trailer) = raw.split_at(size);
+        match trailer[0] {
+            COMPRESSION_SNAPPY => snap::raw::Decoder::new()
+                .decompress_vec(contents)
+                .map_err(|e| corrupt(&self.path, &format!("bad snappy block: {}", e))),
+            _ => Ok(contents.to_vec()),
+        }
+    }
+
+    fn index_block(&self) -> Result<Vec<u8>, SourceError> {
+        let footer = self
+            .data
+            .len()
+            .checked_sub(FOOTER_LEN)
+            .map(|start| &self.data[start..])
+            .ok_or_else(|| corrupt(&self.path, "file too short"))?;
+        let magic = u64::from_le_bytes(footer[FOOTER_LEN - 8..].try_into().unwrap());
+        if magic != TABLE_MAGIC {
+            return Err(corrupt(&self.path, "not a table file"));
+        }
+
+        let mut reader = Reader::new(footer);
+        // The metaindex handle comes first; only the index handle is needed.
+        reader.varint();
+        reader.varint();
+        let index_start = reader.pos;
+        reader.varint();
+        reader.varint();
+        self.block(&footer[index_start..reader.pos])
+    }
+
+    /// Adds every entry whose user key starts with `prefix`.
+    fn read_prefix(&self, prefix: &[u8], entries: &mut Entries) -> Result<(), SourceError> {
+        let index = self.index_block()?;
+        let index_entries =
+            block_entries(&index).ok_or_else(|| corrupt(&self.path, "bad index block"))?;
+
+        for (separator, handle) in index_entries {
+            // Every key in the block sorts at or before its separator.
+            let separator_key = parse_internal_key(&separator)
+                .map(|(user_key, _, _)| user_key)
+                .unwrap_or(&separator);
+            if separator_key < prefix {
+                continue;
+            }
+
+            let block = self.block(handle)?;
+            let block_entries =
+                block_entries(&block).ok_or_else(|| corrupt(&self.path, "bad data block"))?;
+            for (internal_key, value) in block_entries {
+                let (user_key, sequence, value_type) = match parse_internal_key(&internal_key) {
+                    Some(parsed) => parsed,
+                    None => continue,
+                };
+                if user_key.starts_with(prefix) {
+                    match value_type {
+                        TYPE_VALUE => entries.insert(user_key, sequence, Some(value)),
+                        TYPE_DELETION => entries.insert(user_key, sequence, None),
+                        _ => {}
+                    }
+                } else if user_key > prefix {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reassembles the records of a write-ahead log, dropping a torn final record.
+fn log_records(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut pending: Option<Vec<u8>> = None;
+    for block in data.chunks(LOG_BLOCK_LEN) {
+        let mut reader = Reader::new(block);
+        while block.len() - reader.pos >= LOG_HEADER_LEN {
+            let header = (reader.fixed32(), reader.bytes(2), reader.u8());
+            let (len, record_type) = match header {
+                (Some(_crc), Some(len), Some(record_type)) => {
+                    (u16::from_le_bytes([len[0], len[1]]) as usize, record_type)
+                }
+                _ => break,
+            };
+            let fragment = match reader.bytes(len) {
+                Some(fragment) => fragment,
+                None => break,
+            };
+            match record_type {
+                LOG_FULL => records.push(fragment.to_vec()),
+                LOG_FIRST => pending = Some(fragment.to_vec()),
+                LOG_MIDDLE => {
+                    if let Some(pending) = pending.as_mut() {
+                        pending.extend_from_slice(fragment)
+                    }
+                }
+                LOG_LAST => {
+                    if let Some(mut record) = pending.take() {
+                        record.extend_from_slice(fragment);
+                        records.push(record);
+                    }
+                }
+                // Zero type is preallocated padding.
+                _ => break,
+            }
+        }
+    }
+    records
+}
+
+/// Adds the entries of a write batch whose keys start with one of `prefixes`.
+fn read_write_batch(batch: &[u8], prefixes: &[&[u8]], entries: &mut Entries) -> Option<()> {
+    let mut reader = Reader::new(batch);
+    let first_sequence = reader.fixed64()?;
+    let count = reader.fixed32()?;
+    let end_sequence = first_sequence.checked_add(u64::from(count))?;
+    for sequence in first_sequence..end_sequence {
+        let value_type = reader.u8()?;
+        let key_len = reader.varint()? as usize;
+        let key = reader.bytes(key_len)?;
+        let value = match value_type {
+            TYPE_VALUE => {
+                let value_len = reader.varint()? as usize;
+                Some(reader.bytes(value_len)?)
+            }
+            TYPE_DELETION => None,
+            _ => return None,
+        };
+        if prefixes.iter().any(|prefix| key.starts_with(prefix)) {
+            entries.insert(key, sequence, value);
+        }
+    }
+    Some(())
+}
+
+/// The files making up the current version of the database.
+#[derive(Debug, Default, PartialEq)]
+struct Version {
+    tables: HashSet<u64>,
+    log_number: u64,
+    prev_log_number: u64,
+}
+
+impl Version {
+    /// Applies a version edit record from the MANIFEST.
+    fn apply(&mut self, edit: &[u8]) -> Option<()> {
+        let mut reader = Reader::new(edit);
+        while !reader.is_empty() {
+            match reader.varint()? {
+                TAG_COMPARATOR => {
+                    reader.length_prefixed()?;
+                }
+                TAG_LOG_NUMBER => self.log_number = reader.varint()?,
+                TAG_PREV_LOG_NUMBER => self.prev_log_number = reader.varint()?,
+                TAG_NEXT_FILE_NUMBER | TAG_LAST_SEQUENCE => {
+                    reader.varint()?;
+                }
+                TAG_COMPACT_POINTER => {
+                    reader.varint()?;
+                    reader.length_prefixed()?;
+                }
+                TAG_DELETED_FILE => {
+                    let _level = reader.varint()?;
+                    self.tables.remove(&reader.varint()?);
+                }
+                TAG_NEW_FILE => {
+                    let _level = reader.varint()?;
+                    self.tables.insert(reader.varint()?);
+                    let _size = reader.varint()?;
+                    let _smallest = reader.length_prefixed()?;
+                    let _largest = reader.length_prefixed()?;
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    /// Whether the log numbered `number` may hold writes not yet in a table.
+    fn is_live_log(&self, number: u64) -> bool {
+        number >= self.log_number || number == self.prev_log_number
+    }
+}
+
+/// Reads the current version from the MANIFEST that `CURRENT` names, or
+/// `None` if either can't be read.
+fn current_version(dir: &Path) -> Option<Version> {
+    let current = fs::read_to_string(dir.join("CURRENT")).ok()?;
+    let manifest = fs::read(dir.join(current.trim_end())).ok()?;
+    let mut version = Version::default();
+    for edit in log_records(&manifest) {
+        version.apply(&edit)?;
+    }
+    Some(version)
+}
+
+/// The number in a database file name such as `000005.ldb`.
+fn file_number(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Adds the entries of the table at `path` under each of `prefixes`.
+fn read_table(path: PathBuf, prefixes: &[&[u8]], entries: &mut Entries) -> io::Result<()> {
+    let table = Table {
+        data: fs::read(&path)?,
+        path,
+    };
+    for prefix in prefixes {
+        if let Err(err) = table.read_prefix(prefix, entries) {
+            eprintln!("Skipping unreadable LevelDB table {}", err);
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the newest value of every live key in the database at `dir` that
+/// starts with one of `prefixes`.
+pub fn read_prefixes(dir: &Path, prefixes: &[&[u8]]) -> Result<Vec<Entry>, SourceError> {
+    let version = current_version(dir);
+    let mut entries = Entries::default();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let listed = version.as_ref().zip(file_number(&path));
+        let read = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ldb") | Some("sst")
+                if listed.is_none_or(|(version, number)| version.tables.contains(&number)) =>
+            {
+                read_table(path, prefixes, &mut entries)
+            }
+            Some("log") if listed.is_none_or(|(version, number)| version.is_live_log(number)) => {
+                fs::read(&path).map(|data| {
+                    for record in log_records(&data) {
+                        read_write_batch(&record, prefixes, &mut entries);
+                    }
+                })
+            }
+            _ => Ok(()),
+        };
+        match read {
+            // Deleted by a compaction since the directory was listed.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            read => read?,
+        }
+    }
+
+    Ok(entries
+        .newest
+        .into_iter()
+        .filter_map(|(key, (_, value))| Some((key, value?)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const ORIGIN: &[u8] = b"_https://teams.microsoft.com\0";
+
+    /// The database written by `tests/fixtures/leveldb/generate.py`.
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/leveldb/db")
+    }
+
+    /// Reads the Teams origin's entries as `script key => value`.
+    fn read(dir: &Path) -> BTreeMap<String, String> {
+        read_prefixes(dir, &[ORIGIN])
+            .unwrap()
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    String::from_utf8_lossy(&key[ORIGIN.len() + 1..]).into_owned(),
+                    String::from_utf8_lossy(&value[1..]).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    fn expected(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_manifest() {
+        assert_eq!(
+            current_version(&fixture()),
+            Some(Version {
+                tables: [5, 7, 8].into_iter().collect(),
+                log_number: 6,
+                prev_log_number: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn reads_live_files() {
+        // The log overrides and deletes table values and its torn final
+        // record is dropped; the replaced table, the compacted log and the
+        // truncated table are not read.
+        assert_eq!(
+            read(&fixture()),
+            expected(&[
+                ("added", "from the log"),
+                ("token", "new token"),
+                ("unchanged", "from the table"),
+            ])
+        );
+    }
+
+    #[test]
+    fn reads_every_file_without_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        for entry in fs::read_dir(fixture()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() != "CURRENT" {
+                fs::copy(&path, dir.path().join(path.file_name().unwrap())).unwrap();
+            }
+        }
+
+        // The stale files win on sequence number, and the truncated table is
+        // skipped rather than failing the read.
+        assert_eq!(
+            read(dir.path()),
+            expected(&[
+                ("added", "from the log"),
+                ("token", "new token"),
+                ("unchanged", "stale log"),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_sequence_overflow() {
+        let key = [ORIGIN, b"\x01token"].concat();
+        let batch = |first_sequence: u64| {
+            let mut batch = first_sequence.to_le_bytes().to_vec();
+            batch.extend_from_slice(&2u32.to_le_bytes());
+            for _ in 0..2 {
+                batch.push(TYPE_VALUE);
+                batch.push(key.len() as u8);
+                batch.extend_from_slice(&key);
+                batch.extend_from_slice(&[2, 1, b'v']);
+            }
+            batch
+        };
+
+        let mut entries = Entries::default();
+        assert!(read_write_batch(&batch(u64::MAX - 1), &[ORIGIN], &mut entries).is_none());
+        assert!(entries.newest.is_empty());
+        assert!(read_write_batch(&batch(u64::MAX - 2), &[ORIGIN], &mut entries).is_some());
+        assert_eq!(entries.newest[&key].0, u64::MAX - 1);
+    }
+
+    #[test]
+    fn rejects_block_handle_past_end() {
+        let table = Table {
+            path: PathBuf::from("000001.ldb"),
+            data: vec![0; 64],
+        };
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(table.block(&[max, max].concat()).is_err());
+        assert!(table.block(&[60, 10]).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! Teams web token entries, as stored in a browser's Local Storage.
+//!
+//! Chromium and Firefox keep Local Storage in different databases, but the
+//! keys and JSON values Teams writes are the same, so both readers feed their
+//! entries through [`LocalStorageTokens`].
+//!
+//! Two layouts are understood: the legacy Teams web cache (`ts.<oid>.cache.token.<resource>`
+//! and `auth.skype.token`), and the MSAL.js token cache used by the new Teams
+//! client and current Teams web, whose access token keys look like
+//! `<homeAccountId>-login.windows.net-accesstoken-<clientId>-<tenant>-<scopes>`.
+
+use serde::Deserialize;
+
+use super::{SourceError, Token, TokenOrigin};
+use crate::{cloud::Cloud, refresh::RefreshGrant, AccountType};
+
+const SKYPE_TOKEN_SUFFIX: &str = "auth.skype.token";
+/// Legacy presence tokens are stored under this followed by the audience.
+const PRESENCE_TOKEN_MARKER: &str = ".cache.token.";
+const MSAL_ACCESS_TOKEN_MARKER: &str = "-accesstoken-";
+const MSAL_REFRESH_TOKEN_MARKER: &str = "-refreshtoken-";
+
+#[derive(Deserialize, Debug)]
+struct PresenceToken {
+    token: String,
+    expiration: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SkypeToken {
+    skype_token: String,
+    expiration: u64,
+}
+
+/// An MSAL.js access token cache entry.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MsalAccessToken {
+    home_account_id: String,
+    /// Authority host, e.g. `login.windows.net`.
+    environment: String,
+    client_id: String,
+    /// Tenant the token was issued by.
+    realm: String,
+    secret: String,
+    /// Seconds since the epoch, stored as a string.
+    expires_on: String,
+    /// Space separated scopes the token was issued for.
+    target: String,
+}
+
+impl MsalAccessToken {
+    /// Whether one of the token's scopes is for the presence service at
+    /// `presence_host`, e.g. `https://presence.teams.microsoft.com/.default`.
+    fn covers_presence(&self, presence_host: &str) -> bool {
+        self.target.split_whitespace().any(|scope| {
+            let scope = scope.to_lowercase();
+            let scope = scope.strip_prefix("https://").unwrap_or(&scope);
+            scope == presence_host || scope.starts_with(&format!("{}/", presence_host))
+        })
+    }
+}
+
+/// An MSAL.js refresh token cache entry.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MsalRefreshToken {
+    home_account_id: String,
+    client_id: String,
+    /// Set for "family of client IDs" tokens, which any client in the family
+    /// may redeem.
+    family_id: Option<String>,
+    secret: String,
+}
+
+/// The tokens found while scanning a Local Storage database, with the keys
+/// they were stored under.
+#[derive(Debug)]
+pub struct LocalStorageTokens {
+    /// The presence service of the cloud, e.g. `https://presence.teams.microsoft.com/`.
+    presence_audience: String,
+    /// Refresh tokens MSAL.js obtained in the browser are single-page app
+    /// tokens, which Azure AD only redeems for cross-origin requests from
+    /// the app.
+    teams_origin: String,
+    presence_tokens: Vec<(String, PresenceToken)>,
+    skype_tokens: Vec<(String, SkypeToken)>,
+    msal_access_tokens: Vec<(String, MsalAccessToken)>,
+    msal_refresh_tokens: Vec<MsalRefreshToken>,
+}
+
+impl LocalStorageTokens {
+    /// An empty set, for the tokens Teams web in `cloud` stores.
+    pub fn new(cloud: &Cloud) -> Self {
+        LocalStorageTokens {
+            presence_audience: cloud.audience(AccountType::Microsoft),
+            teams_origin: cloud.teams_origins().remove(0),
+            presence_tokens: Vec::new(),
+            skype_tokens: Vec::new(),
+            msal_access_tokens: Vec::new(),
+            msal_refresh_tokens: Vec::new(),
+        }
+    }
+
+    /// Records the entry if `key` names a Teams token. `value` is the JSON
+    /// the Teams web app stored.
+    pub fn add(&mut self, key: &str, value: &[u8]) -> Result<(), SourceError> {
+        if key.ends_with(SKYPE_TOKEN_SUFFIX) {
+            let new_skype_token: SkypeToken = serde_json::from_slice(value).map_err(|e| {
+                SourceError::Read(format!("failed to parse skype token info: {}", e))
+            })?;
+            self.skype_tokens.push((key.to_string(), new_skype_token));
+        } else if key
+            .strip_suffix(self.presence_audience.as_str())
+            .is_some_and(|key| key.ends_with(PRESENCE_TOKEN_MARKER))
+        {
+            let new_presence_token: PresenceToken = serde_json::from_slice(value).map_err(|e| {
+                SourceError::Read(format!("failed to parse presence token info: {}", e))
+            })?;
+            self.presence_tokens
+                .push((key.to_string(), new_presence_token));
+        } else {
+            // Entries MSAL has encrypted, or that aren't tokens after all, don't
+            // parse; they just aren't usable.
+            let lowercase_key = key.to_lowercase();
+            if lowercase_key.contains(MSAL_ACCESS_TOKEN_MARKER) {
+                if let Ok(msal_token) = serde_json::from_slice::<MsalAccessToken>(value) {
+                    let presence_host = self
+                        .presence_audience
+                        .trim_start_matches("https://")
+                        .trim_end_matches('/');
+                    if msal_token.covers_presence(presence_host) {
+                        self.msal_access_tokens.push((key.to_string(), msal_token));
+                    }
+                }
+            } else if lowercase_key.contains(MSAL_REFRESH_TOKEN_MARKER) {
+                if let Ok(msal_token) = serde_json::from_slice::<MsalRefreshToken>(value) {
+                    self.msal_refresh_tokens.push(msal_token);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The refresh token that can renew `access_token`, if MSAL cached one.
+    fn refresh_grant(&self, access_token: &MsalAccessToken) -> Option<RefreshGrant> {
+        let refresh_token = self
+            .msal_refresh_tokens
+            .iter()
+            .filter(|rt| rt.home_account_id == access_token.home_account_id)
+            .find(|rt| rt.client_id == access_token.client_id || rt.family_id.is_some())?;
+        Some(RefreshGrant {
+            token_url: format!(
+                "https://{}/{}/oauth2/v2.0/token",
+                access_token.environment, access_token.realm
+            ),
+            client_id: access_token.client_id.clone(),
+            refresh_token: refresh_token.secret.clone(),
+            scope: access_token.target.clone(),
+            origin: Some(self.teams_origin.clone()),
+            save_to_login: false,
+        })
+    }
+
+    /// Every token found for `account_type`, expired or not. MSAL access
+    /// tokens come with a refresh grant when one was cached alongside them.
+    pub fn into_tokens(self, account_type: AccountType) -> Vec<Token> {
+        match account_type {
+            AccountType::Microsoft => {
+                let msal_tokens = self.msal_access_tokens.iter().map(|(key, t)| Token {
+                    token: t.secret.clone(),
+                    expiration: t.expires_on.parse::<u64>().unwrap_or(0),
+                    refresh: self.refresh_grant(t),
+                    origin: TokenOrigin::key(key),
+                });
+                self.presence_tokens
+                    .iter()
+                    .map(|(key, t)| Token {
+                        token: t.token.clone(),
+                        expiration: t.expiration,
+                        refresh: None,
+                        origin: TokenOrigin::key(key),
+                    })
+                    .chain(msal_tokens)
+                    .collect()
+            }
+            AccountType::Live => self
+                .skype_tokens
+                .into_iter()
+                .map(|(key, t)| Token {
+                    token: t.skype_token,
+                    expiration: t.expiration,
+                    refresh: None,
+                    origin: TokenOrigin::key(&key),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ACCESS_TOKEN_KEY: &str = "uid.utid-login.windows.net-accesstoken-client-tenant-";
+
+    fn msal_access_token(scope: &str) -> Vec<u8> {
+        json!({
+            "homeAccountId": "uid.utid",
+            "environment": "login.windows.net",
+            "clientId": "client",
+            "realm": "tenant",
+            "secret": format!("token for {}", scope),
+            "expiresOn": "4102444800",
+            "target": scope,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn presence_tokens(cloud: Cloud, entries: &[(String, Vec<u8>)]) -> Vec<Token> {
+        let mut tokens = LocalStorageTokens::new(&cloud);
+        for (key, value) in entries {
+            tokens.add(key, value).unwrap();
+        }
+        tokens.into_tokens(AccountType::Microsoft)
+    }
+
+    #[test]
+    fn reads_the_clouds_presence_tokens() {
+        let legacy = |audience: &str| {
+            (
+                format!("ts.oid.cache.token.{}", audience),
+                json!({ "token": audience, "expiration": 4102444800u64 })
+                    .to_string()
+                    .into_bytes(),
+            )
+        };
+        let msal = |scope: &str| {
+            (
+                format!("{}{}", ACCESS_TOKEN_KEY, scope),
+                msal_access_token(scope),
+            )
+        };
+        let entries = [
+            legacy("https://presence.teams.microsoft.com/"),
+            legacy("https://presence.gov.teams.microsoft.us/"),
+            msal("https://presence.teams.microsoft.com/.default"),
+            msal("https://presence.gov.teams.microsoft.us/.default"),
+        ];
+
+        let found = |cloud| {
+            presence_tokens(cloud, &entries)
+                .into_iter()
+                .map(|token| token.token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            found(Cloud::Commercial),
+            [
+                "https://presence.teams.microsoft.com/",
+                "token for https://presence.teams.microsoft.com/.default"
+            ]
+        );
+        assert_eq!(
+            found(Cloud::Gcch),
+            [
+                "https://presence.gov.teams.microsoft.us/",
+                "token for https://presence.gov.teams.microsoft.us/.default"
+            ]
+        );
+    }
+
+    #[test]
+    fn renews_from_the_custom_origin() {
+        let scope = "https://presence.teams.microsoft.com/.default";
+        let cloud = Cloud::Custom("http://127.0.0.1:8080/teams".to_string());
+        let tokens = presence_tokens(
+            cloud,
+            &[
+                (
+                    format!("{}{}", ACCESS_TOKEN_KEY, scope),
+                    msal_access_token(scope),
+                ),
+                (
+                    "uid.utid-login.windows.net-refreshtoken-client--".to_string(),
+                    json!({ "homeAccountId": "uid.utid", "clientId": "client", "secret": "rt" })
+                        .to_string()
+                        .into_bytes(),
+                ),
+            ],
+        );
+        assert_eq!(tokens.len(), 1);
+        let grant = tokens[0].refresh.as_ref().unwrap();
+        assert_eq!(grant.origin.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(grant.refresh_token, "rt");
+    }
+}
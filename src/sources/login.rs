@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use super::{SourceError, Token, TokenOrigin, TokenSource};
+use crate::{login::StoredLogin, refresh::RefreshGrant, AccountType};
+
+/// Tokens from `tpcli login`.
+pub struct LoginSource;
+
+impl TokenSource for LoginSource {
+    fn describe(&self) -> String {
+        format!("tpcli login ({})", StoredLogin::path().display())
+    }
+
+    fn discover(&self, account_type: AccountType) -> Result<(), SourceError> {
+        if let AccountType::Live = account_type {
+            return Err(SourceError::Unsupported(
+                "`tpcli login` only supports work and school accounts".to_string(),
+            ));
+        }
+        let path = StoredLogin::path();
+        if !path.is_file() {
+            return Err(SourceError::NotFound(path));
+        }
+        Ok(())
+    }
+
+    fn paths(&self, _account_type: AccountType) -> Vec<PathBuf> {
+        vec![StoredLogin::path()]
+    }
+
+    fn tokens(&self, _account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        let stored = StoredLogin::load()
+            .map_err(|e| SourceError::Read(e.to_string()))?
+            .ok_or_else(|| SourceError::NoToken("run `tpcli login` first".to_string()))?;
+        let refresh = stored
+            .refresh_token
+            .as_ref()
+            .map(|refresh_token| RefreshGrant {
+                token_url: stored.token_url(),
+                client_id: stored.client_id.clone(),
+                refresh_token: refresh_token.clone(),
+                scope: stored.scope.clone(),
+                origin: None,
+                save_to_login: true,
+            });
+        Ok(vec![Token {
+            token: stored.access_token,
+            expiration: stored.expires_at,
+            refresh,
+            origin: TokenOrigin::file(StoredLogin::path()),
+        }])
+    }
+}
@@ -0,0 +1,527 @@
+//! Token discovery.
+//!
+//! Each place `tpcli` knows how to find a Teams auth token (the Chrome Local
+//! Storage leveldb, the Teams app cookie database, ...) is a [`TokenSource`].
+//! Sources are tried in the order the user asked for, and every failure is
+//! collected so it can be reported if none of them produce a token.
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use crate::{
+    cache::{CachedSource, Fingerprint, TokenCache},
+    cloud::Cloud,
+    jwt::{Claims, Jwt},
+    refresh::RefreshGrant,
+    AccountType,
+};
+
+mod chromium;
+mod cookie_crypto;
+mod explicit;
+mod firefox;
+mod helper;
+mod leveldb;
+mod local_storage;
+mod login;
+mod teams;
+
+pub use chromium::{ChromiumSource, BROWSERS};
+pub use explicit::{ExplicitSource, TokenInput};
+pub use firefox::FirefoxSource;
+pub use helper::HelperSource;
+pub use login::LoginSource;
+pub use teams::TeamsSource;
+
+/// An auth token ready to be sent to the presence service.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Token {
+    pub token: String,
+    pub expiration: u64,
+    /// How to renew the token, when the source also had a refresh token.
+    pub refresh: Option<RefreshGrant>,
+    #[serde(default)]
+    pub origin: TokenOrigin,
+}
+
+/// Where a token was read from, for `tpcli token show`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TokenOrigin {
+    /// The source that found it, filled in by [`find_token`].
+    #[serde(skip)]
+    pub source: String,
+    /// Browser profile.
+    pub profile: Option<String>,
+    /// Database or file holding the token.
+    pub file: Option<PathBuf>,
+    /// Local Storage key or cookie name.
+    pub key: Option<String>,
+}
+
+impl TokenOrigin {
+    fn key(key: &str) -> Self {
+        TokenOrigin {
+            key: Some(key.to_string()),
+            ..TokenOrigin::default()
+        }
+    }
+
+    fn file(file: PathBuf) -> Self {
+        TokenOrigin {
+            file: Some(file),
+            ..TokenOrigin::default()
+        }
+    }
+}
+
+impl Token {
+    /// The identity claims of the token, empty for tokens that aren't JWTs.
+    pub fn claims(&self) -> Claims {
+        Jwt {
+            token: self.token.clone(),
+        }
+        .claims()
+        .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    /// The file or directory backing the source does not exist.
+    NotFound(PathBuf),
+    /// The source cannot be used on this platform or for this account type.
+    Unsupported(String),
+    /// The source was readable but held no usable token.
+    NoToken(String),
+    /// The source exists but could not be read.
+    Read(String),
+    /// A token supplied by the user is malformed or expired.
+    Invalid(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::NotFound(path) => write!(f, "{} does not exist", path.display()),
+            SourceError::Unsupported(reason) => write!(f, "not supported: {}", reason),
+            SourceError::NoToken(reason) => write!(f, "no token found: {}", reason),
+            SourceError::Read(reason) => write!(f, "failed to read: {}", reason),
+            SourceError::Invalid(reason) => write!(f, "invalid token: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<std::io::Error> for SourceError {
+    fn from(err: std::io::Error) -> Self {
+        SourceError::Read(err.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for SourceError {
+    fn from(err: rusqlite::Error) -> Self {
+        SourceError::Read(err.to_string())
+    }
+}
+
+pub trait TokenSource {
+    /// Human readable name of the source, used in error messages.
+    fn describe(&self) -> String;
+
+    /// Checks that the data backing this source exists on this machine.
+    fn discover(&self, account_type: AccountType) -> Result<(), SourceError>;
+
+    /// Returns every token the source holds for `account_type`, including
+    /// expired ones, for the caller to choose from.
+    fn tokens(&self, account_type: AccountType) -> Result<Vec<Token>, SourceError>;
+
+    /// Identifies the current state of the files `tokens` reads, so its result
+    /// can be cached until they change. `None` disables caching.
+    fn fingerprint(&self, _account_type: AccountType) -> Option<Fingerprint> {
+        None
+    }
+
+    /// Whether `tokens` hands out the same tokens whatever the account type,
+    /// so that when the type isn't known, one successful call covers both.
+    fn account_agnostic(&self) -> bool {
+        false
+    }
+
+    /// Files and directories the source reads, whether or not they exist, for
+    /// `tpcli doctor` to check.
+    fn paths(&self, _account_type: AccountType) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Whether tokens read earlier are out of date, e.g. because a token file
+    /// was replaced, so a token in use should be looked up again.
+    fn changed(&self) -> bool {
+        false
+    }
+
+    /// The token [`find_token`] chose from this source for the same lookup
+    /// before, if it is still good to use.
+    fn cached(
+        &self,
+        _account_type: Option<AccountType>,
+        _filter: &AccountFilter,
+    ) -> Option<(Token, AccountType)> {
+        None
+    }
+
+    /// Called with the token [`find_token`] chose from this source, to be
+    /// returned by [`TokenSource::cached`] next time.
+    fn remember(
+        &self,
+        _account_type: Option<AccountType>,
+        _filter: &AccountFilter,
+        _token: &Token,
+        _token_type: AccountType,
+    ) {
+    }
+}
+
+/// Settings shared by the sources, gathered from the command line and config.
+#[derive(Default)]
+pub struct SourceOptions {
+    /// Browser profile to read from, by directory or display name.
+    pub profile: Option<String>,
+    /// Keyring password for encrypted Teams app cookies.
+    pub cookie_password: Option<String>,
+    /// Command to run for the `helper` source.
+    pub token_helper: Option<String>,
+    /// Tenant the token should belong to, passed on to token helpers.
+    pub tenant: Option<String>,
+    /// Cache for tokens from sources that are slow to read.
+    pub token_cache: Option<Arc<TokenCache>>,
+    /// Cloud the token is for.
+    pub cloud: Cloud,
+}
+
+/// Which account to use when sources hold tokens for several, from `--user`
+/// and `--tenant`.
+#[derive(Default)]
+pub struct AccountFilter {
+    /// Sign-in name or object ID.
+    pub user: Option<String>,
+    /// Tenant ID, or a domain the user's sign-in name is in.
+    pub tenant: Option<String>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, claims: &Claims) -> bool {
+        let user_matches = self.user.as_deref().is_none_or(|user| {
+            [claims.user(), claims.oid.as_deref()]
+                .iter()
+                .flatten()
+                .any(|claim| claim.eq_ignore_ascii_case(user))
+        });
+        let domain = claims
+            .user()
+            .and_then(|user| user.rsplit_once('@'))
+            .map(|(_, domain)| domain);
+        let tenant_matches = match self.tenant.as_deref() {
+            // Authority aliases (used by `tpcli login`) aren't a tenant.
+            None | Some("common") | Some("organizations") | Some("consumers") => true,
+            Some(tenant) => [claims.tid.as_deref(), domain]
+                .iter()
+                .flatten()
+                .any(|claim| claim.eq_ignore_ascii_case(tenant)),
+        };
+        user_matches && tenant_matches
+    }
+
+    fn is_empty(&self) -> bool {
+        self.user.is_none() && self.tenant.is_none()
+    }
+}
+
+impl fmt::Display for AccountFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.user, &self.tenant) {
+            (Some(user), Some(tenant)) => write!(f, "{} in tenant {}", user, tenant),
+            (Some(user), None) => write!(f, "{}", user),
+            (None, Some(tenant)) => write!(f, "tenant {}", tenant),
+            (None, None) => write!(f, "any account"),
+        }
+    }
+}
+
+/// Every accepted `--app` value.
+pub const APPS: &[&str] = &[
+    "teams",
+    "new-teams",
+    "chrome",
+    "chromium",
+    "edge",
+    "brave",
+    "vivaldi",
+    "firefox",
+    "helper",
+    "login",
+];
+
+/// Builds the source for an `--app` value.
+pub fn from_name(name: &str, options: &SourceOptions) -> Option<Box<dyn TokenSource>> {
+    let source = uncached_from_name(name, options)?;
+    Some(match &options.token_cache {
+        Some(cache) => Box::new(CachedSource {
+            inner: source,
+            cache: cache.clone(),
+        }),
+        None => source,
+    })
+}
+
+fn uncached_from_name(name: &str, options: &SourceOptions) -> Option<Box<dyn TokenSource>> {
+    match name {
+        "teams" => Some(Box::new(TeamsSource {
+            cookie_password: options.cookie_password.clone(),
+        })),
+        "firefox" => Some(Box::new(FirefoxSource {
+            profile: options.profile.clone(),
+            cloud: options.cloud.clone(),
+        })),
+        "login" => Some(Box::new(LoginSource)),
+        "helper" => options
+            .token_helper
+            .as_ref()
+            .map(|command| -> Box<dyn TokenSource> {
+                Box::new(HelperSource {
+                    command: command.clone(),
+                    tenant: options.tenant.clone(),
+                    cloud: options.cloud.clone(),
+                })
+            }),
+        _ => BROWSERS.iter().find(|browser| browser.app == name).map(
+            |browser| -> Box<dyn TokenSource> {
+                Box::new(ChromiumSource {
+                    browser,
+                    profile: options.profile.clone(),
+                    cloud: options.cloud.clone(),
+                })
+            },
+        ),
+    }
+}
+
+/// Why no token could be chosen.
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// Every source that failed to produce a token, and why.
+    NotFound(Vec<(String, SourceError)>),
+    /// The first source with a token had tokens for both a work or school
+    /// and a personal account, and `--account` wasn't given.
+    Ambiguous(String),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::NotFound(failures) => {
+                write!(f, "No auth token found")?;
+                for (source, err) in failures {
+                    write!(f, "\n  {}: {}", source, err)?;
+                }
+                Ok(())
+            }
+            DiscoveryError::Ambiguous(source) => write!(
+                f,
+                "{} has tokens for both a work or school account and a personal account; \
+                 choose one with `--account ms` or `--account live`",
+                source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+/// Tries each source in order and returns the latest expiring token of the
+/// first one holding a token for the account `filter` selects, along with the
+/// kind of account it belongs to. Expired tokens are only returned when they
+/// can be refreshed.
+///
+/// Without an `account_type`, sources are asked for both kinds of token, and
+/// each token's kind is taken from its claims where they say.
+pub fn find_token(
+    sources: &[Box<dyn TokenSource>],
+    account_type: Option<AccountType>,
+    filter: &AccountFilter,
+) -> Result<(Token, AccountType), DiscoveryError> {
+    find(sources, account_type, filter, true)
+}
+
+/// [`find_token`], reading every source rather than the token cache, whose
+/// tokens lack the refresh token the source may have.
+pub fn find_token_uncached(
+    sources: &[Box<dyn TokenSource>],
+    account_type: Option<AccountType>,
+    filter: &AccountFilter,
+) -> Result<(Token, AccountType), DiscoveryError> {
+    find(sources, account_type, filter, false)
+}
+
+fn find(
+    sources: &[Box<dyn TokenSource>],
+    account_type: Option<AccountType>,
+    filter: &AccountFilter,
+    use_cache: bool,
+) -> Result<(Token, AccountType), DiscoveryError> {
+    let cur_epoch = crate::now();
+    let requested_types = match account_type {
+        Some(account_type) => vec![account_type],
+        None => vec![AccountType::Microsoft, AccountType::Live],
+    };
+
+    let mut failures = Vec::new();
+    for source in sources {
+        if use_cache {
+            if let Some((mut token, token_type)) = source.cached(account_type, filter) {
+                token.origin.source = source.describe();
+                return Ok((token, token_type));
+            }
+        }
+
+        let mut candidates: Vec<(Token, AccountType)> = Vec::new();
+        let mut mismatched = None;
+        let mut errors: Vec<SourceError> = Vec::new();
+        for &requested in &requested_types {
+            let tokens = match source
+                .discover(requested)
+                .and_then(|_| source.tokens(requested))
+            {
+                Ok(tokens) => tokens,
+                Err(err) => {
+                    if !errors.iter().any(|e| e.to_string() == err.to_string()) {
+                        errors.push(err);
+                    }
+                    continue;
+                }
+            };
+            for token in tokens {
+                let detected = token.claims().account_type();
+                if account_type.is_some() && detected.is_some_and(|d| d != requested) {
+                    mismatched = detected;
+                    continue;
+                }
+                // Sources that can't tell accounts apart hand out the same
+                // token for both; unless its claims say, that's ambiguous.
+                let token_type = detected.unwrap_or(requested);
+                if !candidates
+                    .iter()
+                    .any(|(c, t)| c.token == token.token && *t == token_type)
+                {
+                    candidates.push((token, token_type));
+                }
+            }
+            if source.account_agnostic() {
+                break;
+            }
+        }
+
+        if candidates.is_empty() {
+            match (mismatched, account_type) {
+                (Some(detected), Some(requested)) => failures.push((
+                    source.describe(),
+                    SourceError::NoToken(format!(
+                        "its token is for `--account {}`, not `--account {}`",
+                        detected.name(),
+                        requested.name()
+                    )),
+                )),
+                _ => failures.extend(errors.into_iter().map(|err| (source.describe(), err))),
+            }
+            continue;
+        }
+
+        let selected: Vec<(Token, AccountType)> = candidates
+            .into_iter()
+            .filter(|(token, _)| filter.is_empty() || filter.matches(&token.claims()))
+            .collect();
+        if selected.is_empty() {
+            failures.push((
+                source.describe(),
+                SourceError::NoToken(format!("no token for {}", filter)),
+            ));
+            continue;
+        }
+
+        let usable: Vec<(Token, AccountType)> = selected
+            .into_iter()
+            .filter(|(token, _)| token.expiration > cur_epoch || token.refresh.is_some())
+            .collect();
+        if usable
+            .iter()
+            .any(|(_, account_type)| *account_type != usable[0].1)
+        {
+            return Err(DiscoveryError::Ambiguous(source.describe()));
+        }
+        match usable.into_iter().max_by_key(|(token, _)| token.expiration) {
+            Some((mut token, token_type)) => {
+                source.remember(account_type, filter, &token, token_type);
+                token.origin.source = source.describe();
+                return Ok((token, token_type));
+            }
+            None => failures.push((
+                source.describe(),
+                SourceError::NoToken("all tokens have expired".to_string()),
+            )),
+        }
+    }
+    Err(DiscoveryError::NotFound(failures))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A source holding one token, counting how often it is asked for it.
+    struct Counting {
+        token: String,
+        agnostic: bool,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl TokenSource for Counting {
+        fn describe(&self) -> String {
+            "counting".to_string()
+        }
+
+        fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+            Ok(())
+        }
+
+        fn account_agnostic(&self) -> bool {
+            self.agnostic
+        }
+
+        fn tokens(&self, _account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![Token {
+                token: self.token.clone(),
+                expiration: crate::now() + 3600,
+                refresh: None,
+                origin: TokenOrigin::default(),
+            }])
+        }
+    }
+
+    #[test]
+    fn asks_account_agnostic_sources_once() {
+        let token = crate::jwt::fake(serde_json::json!({ "skypeid": "orgid:0b4e0e63" }));
+        for (agnostic, expected_calls) in [(true, 1), (false, 2)] {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let sources: Vec<Box<dyn TokenSource>> = vec![Box::new(Counting {
+                token: token.clone(),
+                agnostic,
+                calls: calls.clone(),
+            })];
+            let (_, account_type) = find_token(&sources, None, &AccountFilter::default()).unwrap();
+            assert_eq!(account_type, AccountType::Microsoft);
+            assert_eq!(calls.load(Ordering::SeqCst), expected_calls);
+        }
+    }
+}
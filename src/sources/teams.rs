@@ -0,0 +1,281 @@
+use rusqlite::Connection;
+use std::{env, path::PathBuf};
+
+use super::{cookie_crypto::decrypt_cookie, SourceError, Token, TokenOrigin, TokenSource};
+use crate::{
+    cache::{fingerprint_files, Fingerprint},
+    jwt::Jwt,
+    AccountType,
+};
+
+/// The Microsoft Teams desktop app, which keeps its tokens in an Electron
+/// cookie database.
+pub struct TeamsSource {
+    /// Keyring password for `v11` encrypted cookies.
+    pub cookie_password: Option<String>,
+}
+
+impl TokenSource for TeamsSource {
+    fn describe(&self) -> String {
+        match teams_dir() {
+            Ok(path) => format!("Microsoft Teams app ({})", path.display()),
+            Err(_) => "Microsoft Teams app".to_string(),
+        }
+    }
+
+    fn discover(&self, account_type: AccountType) -> Result<(), SourceError> {
+        let path = teams_sqlite_path(matches!(account_type, AccountType::Live))?;
+        if !path.is_file() {
+            return Err(SourceError::NotFound(path));
+        }
+        Ok(())
+    }
+
+    fn fingerprint(&self, account_type: AccountType) -> Option<Fingerprint> {
+        let path = teams_sqlite_path(matches!(account_type, AccountType::Live)).ok()?;
+        Some(fingerprint_files([
+            path.with_file_name("Cookies-journal"),
+            path,
+        ]))
+    }
+
+    fn paths(&self, account_type: AccountType) -> Vec<PathBuf> {
+        teams_sqlite_path(matches!(account_type, AccountType::Live))
+            .into_iter()
+            .collect()
+    }
+
+    fn tokens(&self, account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+        let (jwts, cookie) = match account_type {
+            AccountType::Microsoft => (
+                get_auth_sqlite_tokens(self.cookie_password.as_deref())?,
+                "authtoken",
+            ),
+            AccountType::Live => (
+                get_sqlite_tokens(self.cookie_password.as_deref())?,
+                "skypetoken_asm",
+            ),
+        };
+        let path = teams_sqlite_path(matches!(account_type, AccountType::Live))?;
+        jwts.into_iter()
+            .map(|jwt| {
+                Ok(Token {
+                    expiration: jwt.exp().ok_or_else(|| {
+                        SourceError::Read("malformed token in cookie db".to_string())
+                    })?,
+                    token: jwt.token,
+                    refresh: None,
+                    origin: TokenOrigin {
+                        file: Some(path.clone()),
+                        key: Some(cookie.to_string()),
+                        ..TokenOrigin::default()
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+fn teams_dir() -> Result<PathBuf, SourceError> {
+    if cfg!(target_os = "macos") {
+        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+        Ok(home
+            .join("Library")
+            .join("Application Support")
+            .join("Microsoft")
+            .join("Teams"))
+    } else if cfg!(target_os = "windows") {
+        let app_data = env::var("APPDATA")
+            .map_err(|_| SourceError::Read("APPDATA env var not found".to_string()))?;
+        Ok(PathBuf::from(app_data).join("Microsoft").join("Teams"))
+    } else if cfg!(target_os = "linux") {
+        let home = PathBuf::from(env::var("HOME").unwrap_or(String::from("~")));
+        Ok(home
+            .join(".config")
+            .join("Microsoft")
+            .join("Microsoft Teams"))
+    } else {
+        Err(SourceError::Unsupported("unsupported platform".to_string()))
+    }
+}
+
+fn teams_sqlite_path(partition: bool) -> Result<PathBuf, SourceError> {
+    if partition && cfg!(target_os = "linux") {
+        return Err(SourceError::Unsupported(
+            "`--account live` using the Teams app is not supported on linux, use Chrome instead"
+                .to_string(),
+        ));
+    }
+
+    let path = teams_dir()?;
+    let path = if partition && cfg!(target_os = "macos") {
+        path.join("Partitions").join("msa")
+    } else {
+        path
+    };
+
+    Ok(path.join("Cookies"))
+}
+
+/// Reads every cookie called `name`, decrypting `encrypted_value` when the
+/// plaintext `value` column is empty.
+fn read_cookies(
+    sqlite_path: PathBuf,
+    name: &str,
+    password: Option<&str>,
+) -> Result<Vec<String>, SourceError> {
+    let conn = Connection::open(sqlite_path)?;
+    let db_version: u32 = conn
+        .query_row("select value from meta where key = 'version'", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0);
+
+    let mut stmt = conn.prepare("select value, encrypted_value from cookies where name = ?")?;
+    let rows = stmt
+        .query_map([name], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    rows.into_iter()
+        .map(|(value, encrypted_value)| {
+            if value.is_empty() && !encrypted_value.is_empty() {
+                decrypt_cookie(&encrypted_value, password, db_version)
+            } else {
+                Ok(value)
+            }
+        })
+        .collect()
+}
+
+fn get_sqlite_tokens(password: Option<&str>) -> Result<Vec<Jwt>, SourceError> {
+    let sqlite_path = teams_sqlite_path(true)?;
+    let tokens: Vec<Jwt> = read_cookies(sqlite_path, "skypetoken_asm", password)?
+        .into_iter()
+        .map(|token| Jwt { token })
+        .collect();
+
+    if tokens.is_empty() {
+        return Err(SourceError::NoToken(
+            "no skypetoken_asm cookie in MS Teams cookie db".to_string(),
+        ));
+    }
+
+    Ok(tokens)
+}
+
+/// Extracts the token from an `authtoken` cookie, which holds the URL
+/// encoded form `Bearer=<token>&Origin=<origin>`.
+fn parse_auth_cookie(value: &str) -> Result<Jwt, SourceError> {
+    let token_info = urlencoding::decode(value)
+        .map_err(|e| SourceError::Read(format!("authtoken cookie is malformed: {}", e)))?;
+    let bearer_pair = token_info.split('&').next().unwrap_or_default();
+    Ok(Jwt {
+        token: bearer_pair
+            .rsplit('=')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+fn get_auth_sqlite_tokens(password: Option<&str>) -> Result<Vec<Jwt>, SourceError> {
+    let sqlite_path = teams_sqlite_path(false)?;
+    let tokens = read_cookies(sqlite_path, "authtoken", password)?
+        .iter()
+        .map(|value| parse_auth_cookie(value))
+        .collect::<Result<Vec<Jwt>, SourceError>>()?;
+
+    if tokens.is_empty() {
+        return Err(SourceError::NoToken(
+            "no authtoken cookie in MS Teams cookie db".to_string(),
+        ));
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::sources::cookie_crypto::{encrypt, V10_PASSWORD};
+
+    /// A cookie database with `cookies` as `(name, value, encrypted_value)`.
+    fn cookie_db(dir: &tempfile::TempDir, cookies: &[(&str, &str, Vec<u8>)]) -> PathBuf {
+        let path = dir.path().join("Cookies");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "create table meta (key text, value text);
+             insert into meta values ('version', '24');
+             create table cookies (name text, value text, encrypted_value blob);",
+        )
+        .unwrap();
+        for (name, value, encrypted_value) in cookies {
+            conn.execute(
+                "insert into cookies values (?, ?, ?)",
+                rusqlite::params![name, value, encrypted_value],
+            )
+            .unwrap();
+        }
+        path
+    }
+
+    fn host_hashed(value: &str) -> Vec<u8> {
+        let mut plaintext = vec![0; 32];
+        plaintext.extend_from_slice(value.as_bytes());
+        plaintext
+    }
+
+    #[test]
+    fn reads_plain_and_encrypted_cookies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = cookie_db(
+            &dir,
+            &[
+                ("authtoken", "plain", Vec::new()),
+                (
+                    "authtoken",
+                    "",
+                    encrypt(b"v10", V10_PASSWORD, &host_hashed("v10 value")),
+                ),
+                (
+                    "authtoken",
+                    "",
+                    encrypt(b"v11", "keyring", &host_hashed("v11 value")),
+                ),
+                ("skypetoken_asm", "other", Vec::new()),
+            ],
+        );
+        assert_eq!(
+            read_cookies(path, "authtoken", Some("keyring")).unwrap(),
+            ["plain", "v10 value", "v11 value"]
+        );
+    }
+
+    #[test]
+    fn v11_cookies_need_the_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = cookie_db(
+            &dir,
+            &[(
+                "authtoken",
+                "",
+                encrypt(b"v11", "keyring", &host_hashed("v11 value")),
+            )],
+        );
+        assert!(read_cookies(path, "authtoken", None).is_err());
+    }
+
+    #[test]
+    fn parses_auth_cookie() {
+        let token =
+            parse_auth_cookie("Bearer%3Dabc.def.ghi%26Origin%3Dhttps%3A%2F%2Fteams.microsoft.com")
+                .unwrap()
+                .token;
+        assert_eq!(token, "abc.def.ghi");
+        assert!(parse_auth_cookie("Bearer%3D%FF%FE").is_err());
+    }
+}
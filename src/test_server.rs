@@ -0,0 +1,94 @@
+//! A local HTTP server for tests, standing in for Teams, Graph and the
+//! identity provider.
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, HeaderMap, Method, Response, Server, StatusCode,
+};
+use serde_json::Value;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: Method,
+    /// Path and query.
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: String,
+    pub at: Instant,
+}
+
+type Handler = dyn Fn(&Received) -> Option<Response<Body>> + Send + Sync;
+
+pub struct TestServer {
+    /// Base URL, without a trailing slash.
+    pub url: String,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl TestServer {
+    /// Serves every request with `handler`, which never answers when it
+    /// returns `None`.
+    pub fn start(
+        handler: impl Fn(&Received) -> Option<Response<Body>> + Send + Sync + 'static,
+    ) -> TestServer {
+        let handler: Arc<Handler> = Arc::new(handler);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+            let log = log.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let handler = handler.clone();
+                    let log = log.clone();
+                    async move {
+                        let at = Instant::now();
+                        let (parts, body) = req.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                        let received = Received {
+                            method: parts.method,
+                            path: parts
+                                .uri
+                                .path_and_query()
+                                .map(|path| path.to_string())
+                                .unwrap_or_default(),
+                            headers: parts.headers,
+                            body: String::from_utf8_lossy(&body).into_owned(),
+                            at,
+                        };
+                        log.lock().unwrap().push(received.clone());
+                        match handler(&received) {
+                            Some(resp) => Ok::<_, Infallible>(resp),
+                            None => std::future::pending().await,
+                        }
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        TestServer { url, received }
+    }
+
+    /// Every request received so far.
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+/// A JSON response.
+pub fn json(status: u16, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::from_u16(status).unwrap())
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,113 @@
+//! `tpcli token`: inspect and export the token `tpcli` would use.
+
+use std::time::Duration;
+
+use crate::{
+    error::Error,
+    now,
+    output::{epoch_time, print_fields},
+    refresh::TokenManager,
+    sources::{self, AccountFilter, TokenSource},
+    AccountType,
+};
+
+/// How many characters of the token to show when it is redacted.
+const REDACTED_PREFIX_LEN: usize = 12;
+
+/// Prints where the token that would be used was found, and whose it is.
+/// The token itself is redacted unless `reveal` is set.
+pub fn show(
+    sources: &[Box<dyn TokenSource>],
+    account_type: Option<AccountType>,
+    filter: &AccountFilter,
+    reveal: bool,
+) -> Result<(), Error> {
+    let (token, account_type) = sources::find_token(sources, account_type, filter)?;
+    let claims = token.claims();
+    let now = now();
+
+    let mut fields: Vec<(&str, String)> = vec![("Source", token.origin.source.clone())];
+    if let Some(profile) = &token.origin.profile {
+        fields.push(("Profile", profile.clone()));
+    }
+    if let Some(file) = &token.origin.file {
+        fields.push(("File", file.display().to_string()));
+    }
+    if let Some(key) = &token.origin.key {
+        fields.push(("Key", key.clone()));
+    }
+    fields.push(("Account", account_type.name().to_string()));
+    if let Some(user) = claims.user() {
+        let user = match &claims.name {
+            Some(name) => format!("{} <{}>", name, user),
+            None => user.to_string(),
+        };
+        fields.push(("User", user));
+    }
+    if let Some(skypeid) = &claims.skypeid {
+        fields.push(("Skype ID", skypeid.clone()));
+    }
+    if let Some(oid) = &claims.oid {
+        fields.push(("Object ID", oid.clone()));
+    }
+    if let Some(tid) = &claims.tid {
+        fields.push(("Tenant", tid.clone()));
+    }
+    if let Some(aud) = &claims.aud {
+        fields.push(("Audience", aud.to_string()));
+    }
+    if let Some(scp) = &claims.scp {
+        fields.push(("Scopes", scp.clone()));
+    }
+    if let Some(client_id) = claims.client_id() {
+        fields.push(("Client", client_id.to_string()));
+    }
+    if let Some(iat) = claims.iat {
+        fields.push(("Issued", epoch_time(iat)));
+    }
+    let remaining = if token.expiration > now {
+        format!(
+            "in {}",
+            humantime::format_duration(Duration::from_secs(token.expiration - now))
+        )
+    } else {
+        "expired".to_string()
+    };
+    let renewable = if token.refresh.is_some() {
+        ", renewable"
+    } else {
+        ""
+    };
+    fields.push((
+        "Expires",
+        format!(
+            "{} ({}{})",
+            epoch_time(token.expiration),
+            remaining,
+            renewable
+        ),
+    ));
+    fields.push((
+        "Token",
+        if reveal {
+            token.token.clone()
+        } else {
+            let prefix: String = token.token.chars().take(REDACTED_PREFIX_LEN).collect();
+            format!(
+                "{}... ({} characters, use --reveal to show)",
+                prefix,
+                token.token.len()
+            )
+        },
+    ));
+
+    print_fields(&fields);
+    Ok(())
+}
+
+/// Prints the raw token, renewing it first if it is about to expire.
+pub async fn export(tokens: &mut TokenManager<'_>) -> Result<(), Error> {
+    let (token, _) = tokens.token().await?;
+    println!("{}", token);
+    Ok(())
+}
@@ -0,0 +1,449 @@
+//! Trouter, the Teams push notification channel.
+//!
+//! Trouter is a socket.io 0.9 websocket. A client asks the Trouter service
+//! for a socket to connect to under a random endpoint ID, authenticates over
+//! the socket, and then tells other services to deliver notifications to its
+//! Trouter URL. Notifications arrive as HTTP-like requests over the socket,
+//! each of which must be acknowledged.
+//!
+//! Presence subscriptions are made with the presence service, which then
+//! pushes changes for the subscribed users instead of having to be polled.
+
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Method, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{collections::VecDeque, time::Duration};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    cloud::Endpoints,
+    error::Error,
+    presence::{self, auth_header, UserPresence},
+    refresh::TokenManager,
+    who,
+};
+
+/// Path under the Trouter URL that presence notifications are delivered to.
+const PRESENCE_PATH: &str = "TeamsUnifiedPresenceService";
+
+/// How often to ping the socket if the server doesn't say.
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// A notification received over Trouter.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// The socket is authenticated and subscriptions are in place.
+    Connected,
+    /// A subscribed user's presence changed.
+    Presence(UserPresence),
+    /// A notification tpcli doesn't interpret.
+    Other { path: String, body: String },
+}
+
+/// What the Trouter service returns for a new endpoint.
+#[derive(Deserialize, Debug)]
+struct TrouterInfo {
+    /// Base URL of the socket.io server to connect to.
+    socketio: String,
+    /// Base URL services deliver notifications for this endpoint to.
+    surl: String,
+    /// Query parameters to connect and authenticate with.
+    #[serde(default)]
+    connectparams: Map<String, Value>,
+}
+
+/// A notification, delivered as a request over the socket.
+#[derive(Deserialize, Debug)]
+struct TrouterRequest {
+    id: Value,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    body: String,
+}
+
+/// Presence notifications list the changed users, sometimes wrapped in an
+/// object.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PresenceNotification {
+    List(Vec<UserPresence>),
+    Wrapped { presence: Vec<UserPresence> },
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct Trouter {
+    socket: Socket,
+    heartbeat: tokio::time::Interval,
+    pending: VecDeque<Event>,
+}
+
+/// Query string for `params`, plus the endpoint ID.
+fn query(params: &Map<String, Value>, epid: &str) -> String {
+    let mut pairs: Vec<String> = params
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            format!(
+                "{}={}",
+                urlencoding::encode(key),
+                urlencoding::encode(&value)
+            )
+        })
+        .collect();
+    pairs.push("v=v4".to_string());
+    pairs.push(format!("epid={}", epid));
+    pairs.push("auth=true".to_string());
+    pairs.join("&")
+}
+
+fn with_slash(url: &str) -> String {
+    format!("{}/", url.trim_end_matches('/'))
+}
+
+impl Trouter {
+    /// Opens a Trouter socket and subscribes it to the presence of the
+    /// signed-in user and `mris`.
+    pub async fn connect(
+        tokens: &mut TokenManager<'_>,
+        endpoints: &Endpoints,
+        mris: &[String],
+    ) -> Result<Trouter, Error> {
+        let (token, account_type) = tokens.token().await?;
+        let (auth_name, auth_value) = auth_header(account_type, &token);
+        let epid = uuid::Uuid::new_v4().to_string();
+        let client = tokens.client();
+
+        let resp = client
+            .send(|| {
+                Ok(Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("{}?epid={}", endpoints.trouter(), epid))
+                    .header(auth_name, &auth_value)
+                    .body(Body::empty())?)
+            })
+            .await?;
+        let info: TrouterInfo =
+            serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await?)?;
+        let socketio = with_slash(&info.socketio);
+        let query = query(&info.connectparams, &epid);
+
+        // The socket.io handshake returns `<session>:<heartbeat>:<timeout>:<transports>`.
+        let resp = client
+            .send(|| {
+                Ok(Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("{}socket.io/1/?{}", socketio, query))
+                    .header(auth_name, &auth_value)
+                    .body(Body::empty())?)
+            })
+            .await?;
+        let handshake = hyper::body::to_bytes(resp.into_body()).await?;
+        let handshake = String::from_utf8_lossy(&handshake);
+        let mut fields = handshake.trim().split(':');
+        let session = fields
+            .next()
+            .filter(|session| !session.is_empty())
+            .ok_or_else(|| Error::Other("Trouter handshake returned no session".into()))?;
+        let heartbeat = fields
+            .next()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            // Ping well within the interval the server expects.
+            .map(|secs| Duration::from_secs(secs) / 2)
+            .unwrap_or(DEFAULT_HEARTBEAT);
+
+        let socket_url = format!("{}socket.io/1/websocket/{}?{}", socketio, session, query);
+        let socket_url = match socket_url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some(("http", rest)) => format!("ws://{}", rest),
+            _ => socket_url,
+        };
+        let timeout = client.policy.timeout;
+        let (mut socket, _) = tokio::time::timeout(timeout, connect_async(socket_url.as_str()))
+            .await
+            .map_err(|_| Error::Timeout(timeout))?
+            .map_err(|err| {
+                Error::Other(format!("Failed to open the Trouter socket: {}", err).into())
+            })?;
+
+        let authenticate = json!({
+            "name": "user.authenticate",
+            "args": [{
+                "headers": { auth_name: auth_value },
+                "connectparams": info.connectparams,
+            }],
+        });
+        send(&mut socket, format!("5:::{}", authenticate)).await?;
+
+        // Presence changes for ourselves and the users given.
+        let mut subscriptions =
+            vec![json!({ "mri": presence::own_mri(&token, account_type)?, "source": "ups" })];
+        subscriptions.extend(
+            mris.iter()
+                .map(|mri| json!({ "mri": mri, "source": "ups" })),
+        );
+        let subscribe = json!({
+            "trouterUri": format!("{}{}", with_slash(&info.surl), PRESENCE_PATH),
+            "shouldPurgePreviousSubscriptions": true,
+            "subscriptionsToAdd": subscriptions,
+            "subscriptionsToRemove": [],
+        });
+        let subscribe = subscribe.to_string();
+        client
+            .send(|| {
+                Ok(presence::request(
+                    endpoints,
+                    account_type,
+                    &token,
+                    Method::POST,
+                    &format!("v1/pubsub/subscriptions/{}", epid),
+                )
+                .header("Content-Type", "application/json")
+                .body(Body::from(subscribe.clone()))?)
+            })
+            .await?;
+
+        let mut heartbeat = tokio::time::interval(heartbeat);
+        // The first tick is immediate.
+        heartbeat.tick().await;
+        Ok(Trouter {
+            socket,
+            heartbeat,
+            pending: VecDeque::from([Event::Connected]),
+        })
+    }
+
+    /// The next notification, or `None` once the server closes the socket.
+    pub async fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            tokio::select! {
+                _ = self.heartbeat.tick() => send(&mut self.socket, "2::".to_string()).await?,
+                message = self.socket.next() => match message {
+                    Some(Ok(Message::Text(text))) => self.handle(&text).await?,
+                    Some(Ok(Message::Ping(data))) => {
+                        self.socket.send(Message::Pong(data)).await.map_err(socket_error)?
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(None),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(socket_error(err)),
+                },
+            }
+        }
+    }
+
+    /// Handles a socket.io frame: `<type>:<id>:<endpoint>:<data>`.
+    async fn handle(&mut self, frame: &str) -> Result<(), Error> {
+        let mut parts = frame.splitn(4, ':');
+        let kind = parts.next().unwrap_or_default();
+        let data = parts.nth(2).unwrap_or_default();
+        match kind {
+            // Heartbeat, which is echoed.
+            "2" => send(&mut self.socket, "2::".to_string()).await?,
+            // A notification, which must be acknowledged.
+            "3" => {
+                let request: TrouterRequest = serde_json::from_str(data)?;
+                let ack = json!({ "id": request.id, "status": 200, "headers": {}, "body": "" });
+                send(&mut self.socket, format!("3:::{}", ack)).await?;
+                if request.url.contains(PRESENCE_PATH) {
+                    if let Ok(notification) = serde_json::from_str(&request.body) {
+                        let users = match notification {
+                            PresenceNotification::List(users) => users,
+                            PresenceNotification::Wrapped { presence } => presence,
+                        };
+                        self.pending.extend(users.into_iter().map(Event::Presence));
+                        return Ok(());
+                    }
+                }
+                self.pending.push_back(Event::Other {
+                    path: request.url,
+                    body: request.body,
+                });
+            }
+            // Disconnect.
+            "0" => {
+                return Err(Error::Other("Trouter closed the session".into()));
+            }
+            // Error.
+            "7" => {
+                return Err(Error::Other(format!("Trouter error: {}", data).into()));
+            }
+            // Connect acknowledgements and events such as `trouter.connected`
+            // need no reply.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+async fn send(socket: &mut Socket, frame: String) -> Result<(), Error> {
+    socket
+        .send(Message::Text(frame))
+        .await
+        .map_err(socket_error)
+}
+
+fn socket_error(err: tokio_tungstenite::tungstenite::Error) -> Error {
+    Error::Other(format!("Trouter socket failed: {}", err).into())
+}
+
+/// Longest to wait before reconnecting.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Prints notifications for the signed-in user and `users` as JSON lines,
+/// reconnecting whenever the socket closes.
+pub async fn events(
+    tokens: &mut TokenManager<'_>,
+    endpoints: &Endpoints,
+    users: &[&str],
+) -> Result<(), Error> {
+    let mris = who::resolve(tokens, endpoints, users).await?;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match Trouter::connect(tokens, endpoints, &mris).await {
+            Ok(mut trouter) => loop {
+                match trouter.next_event().await {
+                    Ok(Some(event)) => {
+                        delay = Duration::from_secs(1);
+                        println!("{}", serde_json::to_string(&event)?);
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        break;
+                    }
+                }
+            },
+            Err(err) if err.is_transient() => eprintln!("{}", err),
+            Err(err) => return Err(err),
+        }
+        eprintln!(
+            "Reconnecting to Trouter in {}",
+            humantime::format_duration(delay)
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A `Trouter` on a socket to a local websocket server, and the server's
+    /// end of it.
+    async fn trouter() -> (Trouter, WebSocketStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let accept = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        let (socket, _) = connect_async(url.as_str()).await.unwrap();
+        let trouter = Trouter {
+            socket,
+            heartbeat: tokio::time::interval(DEFAULT_HEARTBEAT),
+            pending: VecDeque::new(),
+        };
+        (trouter, accept.await.unwrap())
+    }
+
+    async fn received(server: &mut WebSocketStream<TcpStream>) -> String {
+        match server.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    fn events(trouter: &mut Trouter) -> Vec<Value> {
+        trouter
+            .pending
+            .drain(..)
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect()
+    }
+
+    fn notification(id: u64, url: &str, body: Value) -> String {
+        let request = json!({ "id": id, "method": "POST", "url": url, "body": body.to_string() });
+        format!("3:::{}", request)
+    }
+
+    #[tokio::test]
+    async fn handles_frames() {
+        let (mut trouter, mut server) = trouter().await;
+
+        // Connect acknowledgements and events need no reply.
+        trouter.handle("1::").await.unwrap();
+        trouter
+            .handle(r#"5:::{"name":"trouter.connected","args":[{"url":"https://x"}]}"#)
+            .await
+            .unwrap();
+        trouter.handle("2::").await.unwrap();
+        assert_eq!(received(&mut server).await, "2::");
+
+        let url = format!("https://pub.trouter.example:443/v4/f/abc/{}", PRESENCE_PATH);
+        trouter
+            .handle(&notification(
+                7,
+                &url,
+                json!([{ "mri": "8:orgid:a", "presence": { "availability": "Busy" } }]),
+            ))
+            .await
+            .unwrap();
+        trouter
+            .handle(&notification(
+                8,
+                &url,
+                json!({ "presence": [{ "mri": "8:orgid:b", "presence": { "availability": "Away" } }] }),
+            ))
+            .await
+            .unwrap();
+        trouter
+            .handle(&notification(9, "https://x/v4/f/abc/other", json!("hi")))
+            .await
+            .unwrap();
+
+        for id in [7, 8, 9] {
+            let ack: Value =
+                serde_json::from_str(received(&mut server).await.strip_prefix("3:::").unwrap())
+                    .unwrap();
+            assert_eq!(
+                ack,
+                json!({ "id": id, "status": 200, "headers": {}, "body": "" })
+            );
+        }
+        let events = events(&mut trouter);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["type"], "presence");
+        assert_eq!(events[0]["mri"], "8:orgid:a");
+        assert_eq!(events[0]["presence"]["availability"], "Busy");
+        assert_eq!(events[1]["mri"], "8:orgid:b");
+        assert_eq!(
+            events[2],
+            json!({ "type": "other", "path": "https://x/v4/f/abc/other", "body": "\"hi\"" })
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_on_disconnect_and_error_frames() {
+        let (mut trouter, _server) = trouter().await;
+        assert_eq!(
+            trouter.handle("7:::1+0").await.unwrap_err().to_string(),
+            "Trouter error: 1+0"
+        );
+        assert!(trouter.handle("0::").await.is_err());
+        assert!(trouter.handle("3:::not json").await.is_err());
+        assert!(trouter.pending.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! `tpcli watch`: following other users' presence until it changes.
+
+use chrono::Local;
+use colored::*;
+use std::time::Duration;
+
+use crate::{
+    cloud::Endpoints,
+    error::Error,
+    presence::{self, availability_colored, find, PresenceState, UserPresence},
+    refresh::TokenManager,
+    trouter::{Event, Trouter},
+    who, Presence,
+};
+
+/// Shortest `--interval`, to stay clear of the service's rate limits.
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest to back off to after errors, unless the interval is longer.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// What a user's presence is reported as, for telling when it changes.
+#[derive(PartialEq)]
+struct Snapshot {
+    availability: String,
+    activity: Option<String>,
+    message: Option<String>,
+}
+
+impl Snapshot {
+    fn new(state: Option<&PresenceState>) -> Self {
+        Snapshot {
+            availability: state
+                .and_then(|state| state.availability.clone())
+                .unwrap_or_else(|| "PresenceUnknown".to_string()),
+            activity: state.and_then(|state| state.activity.clone()),
+            message: state
+                .and_then(|state| state.note.as_ref())
+                .map(|note| note.text().0.to_string())
+                .filter(|message| !message.is_empty()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut description = availability_colored(&self.availability).to_string();
+        if let Some(activity) = &self.activity {
+            description.push_str(&format!(" ({})", activity));
+        }
+        if let Some(message) = &self.message {
+            description.push_str(&format!(" \"{}\"", message.cyan()));
+        }
+        description
+    }
+}
+
+/// The users being watched and what was last printed for each.
+struct Watcher<'a> {
+    users: &'a [&'a str],
+    mris: Vec<String>,
+    last: Vec<Option<Snapshot>>,
+    until: Option<Presence>,
+}
+
+impl Watcher<'_> {
+    /// Prints the changes `presences` report, and returns whether every user
+    /// now has the `until` status. Users left out keep their last state.
+    fn update(&mut self, presences: &[UserPresence]) -> bool {
+        let time = Local::now().format("%H:%M:%S");
+        for ((user, mri), last) in self.users.iter().zip(&self.mris).zip(self.last.iter_mut()) {
+            let state = find(presences, mri).map(|p| &p.presence);
+            if state.is_none() && last.is_some() {
+                continue;
+            }
+            let snapshot = Snapshot::new(state);
+            match last {
+                Some(last) if *last == snapshot => continue,
+                Some(last) => println!(
+                    "{} {}: {} -> {}",
+                    time,
+                    user,
+                    last.describe(),
+                    snapshot.describe()
+                ),
+                None => println!("{} {}: {}", time, user, snapshot.describe()),
+            }
+            *last = Some(snapshot);
+        }
+
+        match &self.until {
+            Some(until) => self.last.iter().all(|snapshot| {
+                snapshot.as_ref().is_some_and(|snapshot| {
+                    Presence::from_availability(&snapshot.availability).as_ref() == Some(until)
+                })
+            }),
+            None => false,
+        }
+    }
+}
+
+/// Follows pushed presence changes until every user has the `until` status,
+/// returning `true`, or the socket closes, returning `false`.
+async fn follow(
+    tokens: &mut TokenManager<'_>,
+    endpoints: &Endpoints,
+    watcher: &mut Watcher<'_>,
+) -> Result<bool, Error> {
+    let mut trouter = Trouter::connect(tokens, endpoints, &watcher.mris).await?;
+    while let Some(event) = trouter.next_event().await? {
+        if let Event::Presence(user) = event {
+            if watcher.update(std::slice::from_ref(&user)) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Polls the presence of `users` every `interval`, printing each change. With
+/// `until`, returns once every user has that status.
+///
+/// With `push`, changes are pushed over Trouter instead, and polling only
+/// fills in while the socket is being reconnected.
+pub async fn watch(
+    tokens: &mut TokenManager<'_>,
+    endpoints: &Endpoints,
+    users: &[&str],
+    interval: Duration,
+    until: Option<Presence>,
+    push: bool,
+) -> Result<(), Error> {
+    if interval < MIN_INTERVAL {
+        return Err(Error::Usage(format!(
+            "`--interval` must be at least {}",
+            humantime::format_duration(MIN_INTERVAL)
+        )));
+    }
+    let mut watcher = Watcher {
+        users,
+        mris: who::resolve(tokens, endpoints, users).await?,
+        last: users.iter().map(|_| None).collect(),
+        until,
+    };
+    let mut delay = interval;
+
+    loop {
+        // Renews the token when it is about to expire.
+        let result = match tokens.token().await {
+            Ok((token, account_type)) => {
+                presence::get_presence(
+                    tokens.client(),
+                    endpoints,
+                    &token,
+                    account_type,
+                    &watcher.mris,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(presences) => {
+                delay = interval;
+                if watcher.update(&presences) {
+                    return Ok(());
+                }
+                if push {
+                    match follow(tokens, endpoints, &mut watcher).await {
+                        Ok(true) => return Ok(()),
+                        Ok(false) => eprintln!("Trouter closed the socket, reconnecting"),
+                        Err(err) => eprintln!("{}; polling until reconnected", err),
+                    }
+                }
+            }
+            Err(Error::RateLimited { retry_after }) => {
+                delay = retry_after
+                    .unwrap_or_else(|| (delay * 2).min(MAX_BACKOFF))
+                    .max(interval);
+                eprintln!(
+                    "Teams is limiting requests, waiting {}",
+                    humantime::format_duration(delay)
+                );
+            }
+            // Keep watching through network blips and server errors.
+            Err(err) if err.is_transient() => {
+                delay = (delay * 2).min(MAX_BACKOFF).max(interval);
+                eprintln!("{}; retrying in {}", err, humantime::format_duration(delay));
+            }
+            Err(err) => return Err(err),
+        }
+
+        tokio::time::sleep(delay).await;
+    }
+}
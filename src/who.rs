@@ -0,0 +1,269 @@
+//! `tpcli who`: other users' presence.
+
+use colored::*;
+
+use crate::{
+    cloud::Endpoints,
+    error::Error,
+    graph,
+    output::print_table,
+    presence::{self, availability_colored, find},
+    refresh::TokenManager,
+    AccountType,
+};
+
+/// Whether `user` looks like an Azure AD object ID.
+fn is_object_id(user: &str) -> bool {
+    user.len() == 36
+        && user.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// The MRI for `user` if it can be told without a lookup: an MRI itself, an
+/// object ID, or a Skype ID such as `live:alice`.
+fn direct_mri(user: &str, account_type: AccountType) -> Option<String> {
+    if user.starts_with("8:") {
+        Some(user.to_string())
+    } else if user.starts_with("live:") {
+        Some(format!("8:{}", user))
+    } else if account_type == AccountType::Microsoft && is_object_id(user) {
+        Some(format!("8:orgid:{}", user.to_lowercase()))
+    } else {
+        None
+    }
+}
+
+/// The MRIs of `users`, in order. Sign-in names are looked up in Microsoft
+/// Graph, which needs a token with a refresh token to redeem for Graph.
+pub async fn resolve(
+    tokens: &mut TokenManager<'_>,
+    endpoints: &Endpoints,
+    users: &[&str],
+) -> Result<Vec<String>, Error> {
+    let (_, account_type) = tokens.token().await?;
+    let mut graph_token: Option<String> = None;
+    let mut mris = Vec::new();
+    for &user in users {
+        if let Some(mri) = direct_mri(user, account_type) {
+            mris.push(mri);
+            continue;
+        }
+        if account_type == AccountType::Live {
+            return Err(Error::Usage(format!(
+                "Can't look up `{}`: give personal accounts by Skype ID, e.g. `live:alice`",
+                user
+            )));
+        }
+        if graph_token.is_none() {
+            let token = graph::token(tokens, endpoints)
+                .await
+                .map_err(|err| match err {
+                    Error::Other(err) => Error::from(format!(
+                        "Can't look up `{}` by sign-in name ({}); give an object ID or MRI \
+                         instead, or sign in with `tpcli login`",
+                        user, err
+                    )),
+                    err => err,
+                })?;
+            graph_token = Some(token);
+        }
+        if let Some(graph_token) = &graph_token {
+            let id = graph::user_id(tokens.client(), &endpoints.graph(), graph_token, user).await?;
+            mris.push(format!("8:orgid:{}", id));
+        }
+    }
+    Ok(mris)
+}
+
+/// Prints a table of the availability, activity and message of `users`.
+pub async fn who(
+    tokens: &mut TokenManager<'_>,
+    endpoints: &Endpoints,
+    users: &[&str],
+) -> Result<(), Error> {
+    let mris = resolve(tokens, endpoints, users).await?;
+    let (token, account_type) = tokens.token().await?;
+    let presences =
+        presence::get_presence(tokens.client(), endpoints, &token, account_type, &mris).await?;
+
+    let rows: Vec<[String; 4]> = users
+        .iter()
+        .zip(&mris)
+        .map(|(user, mri)| {
+            let state = find(&presences, mri).map(|presence| &presence.presence);
+            let availability = state
+                .and_then(|state| state.availability.as_deref())
+                .unwrap_or("PresenceUnknown");
+            let activity = state
+                .and_then(|state| state.activity.clone())
+                .unwrap_or_else(|| "-".to_string());
+            let message = state
+                .and_then(|state| state.note.as_ref())
+                .map(|note| {
+                    note.text()
+                        .0
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .filter(|message| !message.is_empty())
+                .unwrap_or_else(|| "-".to_string());
+            [
+                user.to_string(),
+                availability_colored(availability).to_string(),
+                activity,
+                message.cyan().to_string(),
+            ]
+        })
+        .collect();
+
+    print_table(["USER", "AVAILABILITY", "ACTIVITY", "MESSAGE"], &rows);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        http::RetryPolicy,
+        jwt, now,
+        refresh::RefreshGrant,
+        sources::{AccountFilter, SourceError, Token, TokenOrigin, TokenSource},
+        test_server::{self, TestServer},
+    };
+
+    const OBJECT_ID: &str = "6f1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d";
+
+    /// A presence token that can be renewed at `token_url`.
+    struct Renewable {
+        token_url: Option<String>,
+    }
+
+    impl TokenSource for Renewable {
+        fn describe(&self) -> String {
+            "renewable".to_string()
+        }
+
+        fn discover(&self, _account_type: AccountType) -> Result<(), SourceError> {
+            Ok(())
+        }
+
+        fn tokens(&self, _account_type: AccountType) -> Result<Vec<Token>, SourceError> {
+            Ok(vec![Token {
+                token: jwt::fake(json!({ "aud": "https://api.spaces.skype.com" })),
+                expiration: now() + 3600,
+                refresh: self.token_url.clone().map(|token_url| RefreshGrant {
+                    token_url,
+                    client_id: "client".to_string(),
+                    refresh_token: "refresh".to_string(),
+                    scope: "https://api.spaces.skype.com/.default".to_string(),
+                    origin: None,
+                    save_to_login: false,
+                }),
+                origin: TokenOrigin::default(),
+            }])
+        }
+    }
+
+    /// Graph and the token endpoint, which hands out Graph tokens unless
+    /// `reject` is set.
+    fn graph_server(reject: bool) -> TestServer {
+        TestServer::start(move |request| {
+            Some(if request.path.ends_with("/oauth2/v2.0/token") {
+                if reject {
+                    test_server::json(400, json!({ "error": "invalid_grant" }))
+                } else {
+                    test_server::json(
+                        200,
+                        json!({
+                            "access_token": jwt::fake(json!({ "aud": "https://graph.microsoft.com" })),
+                            "expires_in": 3600,
+                        }),
+                    )
+                }
+            } else if request.path == "/v1.0/users/alice%40contoso.com?$select=id" {
+                test_server::json(200, json!({ "id": OBJECT_ID }))
+            } else {
+                test_server::json(404, json!({}))
+            })
+        })
+    }
+
+    async fn resolve_with(
+        source: Renewable,
+        server: &TestServer,
+        users: &[&str],
+    ) -> Result<Vec<String>, Error> {
+        let sources: Vec<Box<dyn TokenSource>> = vec![Box::new(source)];
+        let mut tokens = TokenManager::new(
+            &sources,
+            Some(AccountType::Microsoft),
+            AccountFilter::default(),
+            RetryPolicy::default(),
+        );
+        let config = Config {
+            graph_url: Some(server.url.clone()),
+            ..Config::default()
+        };
+        let endpoints = Endpoints::new(Default::default(), &config);
+        resolve(&mut tokens, &endpoints, users).await
+    }
+
+    #[tokio::test]
+    async fn looks_up_sign_in_names() {
+        let server = graph_server(false);
+        let source = Renewable {
+            token_url: Some(format!("{}/tenant/oauth2/v2.0/token", server.url)),
+        };
+
+        let mris = resolve_with(
+            source,
+            &server,
+            &["alice@contoso.com", "live:bob", &OBJECT_ID.to_uppercase()],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            mris,
+            [
+                format!("8:orgid:{}", OBJECT_ID),
+                "8:live:bob".to_string(),
+                format!("8:orgid:{}", OBJECT_ID),
+            ]
+        );
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        assert!(received[1].headers["Authorization"]
+            .to_str()
+            .unwrap()
+            .starts_with("Bearer "));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_category_of_graph_token_errors() {
+        let server = graph_server(true);
+        let source = Renewable {
+            token_url: Some(format!("{}/tenant/oauth2/v2.0/token", server.url)),
+        };
+        let err = resolve_with(source, &server, &["alice@contoso.com"])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unauthorized { .. }), "{:?}", err);
+
+        // Nothing to renew the token with.
+        let err = resolve_with(
+            Renewable { token_url: None },
+            &server,
+            &["alice@contoso.com"],
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::Other(_)), "{:?}", err);
+        assert!(err.to_string().contains("tpcli login"));
+    }
+}
@@ -0,0 +1,239 @@
+#!/usr/bin/env python3
+"""Writes the LevelDB fixture used by the tests in src/sources/leveldb.rs.
+
+The database in `db/` looks like Chromium's Local Storage caught mid
+compaction:
+
+- 000005.ldb  live table, two data blocks, the second Snappy compressed
+- 000003.ldb  table an earlier compaction replaced, no longer in the MANIFEST
+- 000007.ldb  listed in the MANIFEST but cut short, as if half copied
+- 000008.ldb  listed in the MANIFEST but already deleted
+- 000006.log  live write-ahead log, ending in a torn record
+- 000002.log  log already compacted into 000005.ldb
+
+Run it from this directory to regenerate the files.
+"""
+
+import os
+import struct
+
+ORIGIN = b"_https://teams.microsoft.com\x00"
+OTHER_ORIGIN = b"_https://example.com\x00"
+
+TYPE_DELETION = 0
+TYPE_VALUE = 1
+
+
+def crc32c(data):
+    crc = 0xFFFFFFFF
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ (0x82F63B78 if crc & 1 else 0)
+    return crc ^ 0xFFFFFFFF
+
+
+def masked_crc(data):
+    crc = crc32c(data)
+    return (((crc >> 15) | (crc << 17)) + 0xA282EAD8) & 0xFFFFFFFF
+
+
+def varint(n):
+    out = bytearray()
+    while n >= 0x80:
+        out.append((n & 0x7F) | 0x80)
+        n >>= 7
+    out.append(n)
+    return bytes(out)
+
+
+def length_prefixed(data):
+    return varint(len(data)) + data
+
+
+def internal_key(user_key, sequence, value_type):
+    return user_key + struct.pack("<Q", (sequence << 8) | value_type)
+
+
+def block(entries):
+    """A table block of sorted (key, value) pairs, prefix compressed."""
+    out = bytearray()
+    previous = b""
+    for key, value in entries:
+        shared = 0
+        while shared < min(len(key), len(previous)) and key[shared] == previous[shared]:
+            shared += 1
+        out += varint(shared) + varint(len(key) - shared) + varint(len(value))
+        out += key[shared:] + value
+        previous = key
+    return bytes(out) + struct.pack("<II", 0, 1)
+
+
+def snappy_literal(data):
+    """Snappy encoding of `data` as a single literal, which is valid output."""
+    out = varint(len(data))
+    n = len(data) - 1
+    if n < 60:
+        out += bytes([n << 2])
+    elif n < 0x100:
+        out += bytes([60 << 2, n])
+    else:
+        out += bytes([61 << 2]) + struct.pack("<H", n)
+    return out + data
+
+
+def table(blocks):
+    """A table file holding `blocks`, each a list of (internal key, value)
+    pairs and whether to compress it."""
+    out = bytearray()
+    index = []
+
+    def write_block(contents, compression):
+        offset = len(out)
+        out.extend(contents)
+        trailer = bytes([compression])
+        out.extend(trailer + struct.pack("<I", masked_crc(contents + trailer)))
+        return varint(offset) + varint(len(contents))
+
+    for entries, compressed in blocks:
+        contents = block(entries)
+        if compressed:
+            handle = write_block(snappy_literal(contents), 1)
+        else:
+            handle = write_block(contents, 0)
+        index.append((entries[-1][0], handle))
+    metaindex_handle = write_block(block([]), 0)
+    index_handle = write_block(block(index), 0)
+    footer = metaindex_handle + index_handle
+    footer += b"\x00" * (40 - len(footer))
+    out += footer + struct.pack("<Q", 0xDB4775248B80FB57)
+    return bytes(out)
+
+
+def log(records):
+    """A write-ahead log of `records`, which must each fit in one block."""
+    out = bytearray()
+    for record in records:
+        header = struct.pack("<HB", len(record), 1)
+        out += struct.pack("<I", masked_crc(bytes([1]) + record)) + header + record
+    return bytes(out)
+
+
+def batch(sequence, operations):
+    out = bytearray(struct.pack("<QI", sequence, len(operations)))
+    for key, value in operations:
+        if value is None:
+            out += bytes([TYPE_DELETION]) + length_prefixed(key)
+        else:
+            out += bytes([TYPE_VALUE]) + length_prefixed(key) + length_prefixed(value)
+    return bytes(out)
+
+
+def storage(key):
+    """A Local Storage key: the origin, then the Latin-1 encoded script key."""
+    return ORIGIN + b"\x01" + key
+
+
+def value(text):
+    return b"\x01" + text
+
+
+def manifest(edits):
+    return log(edits)
+
+
+def version_edit(
+    log_number=None, next_file=None, last_sequence=None, new_files=(), deleted_files=()
+):
+    out = bytearray()
+    out += varint(1) + length_prefixed(b"leveldb.BytewiseComparator")
+    if log_number is not None:
+        out += varint(2) + varint(log_number)
+    if next_file is not None:
+        out += varint(3) + varint(next_file)
+    if last_sequence is not None:
+        out += varint(4) + varint(last_sequence)
+    for level, number in deleted_files:
+        out += varint(6) + varint(level) + varint(number)
+    for level, number, size, smallest, largest in new_files:
+        out += varint(7) + varint(level) + varint(number) + varint(size)
+        out += length_prefixed(smallest) + length_prefixed(largest)
+    return bytes(out)
+
+
+def main():
+    os.makedirs("db", exist_ok=True)
+    os.chdir("db")
+    for name in os.listdir("."):
+        os.remove(name)
+
+    # Live table: keys of another origin around the Teams ones, so reading has
+    # to skip past them, and a second, compressed block.
+    first_block = [
+        (internal_key(OTHER_ORIGIN + b"\x01a", 1, TYPE_VALUE), value(b"other")),
+        (internal_key(storage(b"deleted"), 2, TYPE_VALUE), value(b"deleted in the log")),
+        (internal_key(storage(b"token"), 3, TYPE_VALUE), value(b"old token")),
+    ]
+    second_block = [
+        (internal_key(storage(b"unchanged"), 4, TYPE_VALUE), value(b"from the table")),
+        (internal_key(b"_https://zz.example\x00\x01z", 5, TYPE_VALUE), value(b"other")),
+    ]
+    live = table([(first_block, False), (second_block, True)])
+    with open("000005.ldb", "wb") as f:
+        f.write(live)
+
+    # A table a compaction replaced. Its value has a higher sequence number
+    # than the live one, so reading it would be noticed.
+    with open("000003.ldb", "wb") as f:
+        f.write(table([([(internal_key(storage(b"unchanged"), 90, TYPE_VALUE), value(b"stale"))], False)]))
+
+    # Listed in the MANIFEST, but only the first half of it is there.
+    listed = table([([(internal_key(storage(b"truncated"), 6, TYPE_VALUE), value(b"lost"))], False)])
+    with open("000007.ldb", "wb") as f:
+        f.write(listed[: len(listed) // 2])
+
+    with open("000002.log", "wb") as f:
+        f.write(log([batch(91, [(storage(b"unchanged"), value(b"stale log"))])]))
+
+    # The last write was cut off part way through its record.
+    with open("000006.log", "wb") as f:
+        records = log(
+            [
+                batch(10, [(storage(b"token"), value(b"new token"))]),
+                batch(11, [(storage(b"deleted"), None), (storage(b"added"), value(b"from the log"))]),
+                batch(12, [(storage(b"torn"), value(b"never committed"))]),
+            ]
+        )
+        f.write(records[:-10])
+
+    with open("MANIFEST-000004", "wb") as f:
+        f.write(
+            manifest(
+                [
+                    version_edit(
+                        log_number=2,
+                        next_file=4,
+                        last_sequence=91,
+                        new_files=[(0, 3, 100, storage(b"unchanged"), storage(b"unchanged"))],
+                    ),
+                    version_edit(
+                        log_number=6,
+                        next_file=9,
+                        last_sequence=11,
+                        deleted_files=[(0, 3)],
+                        new_files=[
+                            (1, 5, len(live), OTHER_ORIGIN, b"_https://zz.example\x00"),
+                            (1, 7, len(listed), storage(b"truncated"), storage(b"truncated")),
+                            (1, 8, 100, storage(b"vanished"), storage(b"vanished")),
+                        ],
+                    ),
+                ]
+            )
+        )
+
+    with open("CURRENT", "w") as f:
+        f.write("MANIFEST-000004\n")
+
+
+if __name__ == "__main__":
+    main()